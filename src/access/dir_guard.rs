@@ -0,0 +1,346 @@
+use crate::{global::{SgStatusCode, Usage, LeaseUse}, storage::Storage, PrngToken, ExpiryAction, AccessLevel, Scheme, SchemeKind};
+
+use anyhow::{Result, bail};
+use std::path::{Component, Path, PathBuf};
+
+/// ### Guards access to a directory subtree
+/// Requested paths are relative to the guarded root. A path containing `..` is rejected outright and
+/// every other path is canonicalized, resolving symlinks, so that a symlink pointing out of the root
+/// is rejected as well. A path that does not exist yet is checked through its canonicalized parent.
+/// Tokens are matched against the canonical path relative to the root, starting with `/`,
+/// and a `Grant` over a directory is inherited by everything below it. A token bound to a `dir:` scheme
+/// is only allowed if the canonical path, including the root, is within the path of its scheme.
+/// `AccessLevel::Read` is a `Usage::Download` and `Create`, `Write` and `Append` are a `Usage::Upload`
+/// of the token, so `Lease::OnDownload` and `Lease::OnUpload` tokens are consumed by the access
+#[derive(Debug, Clone)]
+pub struct DirGuard {
+    root: PathBuf,
+}
+
+impl DirGuard {
+    /// Guard the directory at `root`
+    pub async fn new(root: &Path) -> Result<Self> {
+        let root = async_fs::canonicalize(root).await?;
+        if !async_fs::metadata(&root).await?.is_dir() {
+            bail!("`{}` is not a directory", root.display());
+        }
+
+        Ok(Self { root })
+    }
+
+    pub fn get_root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Resolve `requested` to a canonical path within the root.
+    /// Returns `None` if the path escapes the root
+    pub async fn resolve(&self, requested: &Path) -> Result<Option<PathBuf>> {
+        let mut relative = PathBuf::new();
+        for component in requested.components() {
+            match component {
+                Component::Normal(segment) => relative.push(segment),
+                Component::RootDir | Component::CurDir => (),
+                Component::ParentDir | Component::Prefix(_) => return Ok(None),
+            }
+        }
+
+        let joined = self.root.join(&relative);
+        let canonical = match async_fs::canonicalize(&joined).await {
+            Ok(canonical) => canonical,
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => {
+                match (joined.parent(), joined.file_name()) {
+                    (Some(parent), Some(name)) => match async_fs::canonicalize(parent).await {
+                        Ok(parent) => parent.join(name),
+                        Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+                        Err(error) => return Err(error.into()),
+                    },
+                    _ => return Ok(None),
+                }
+            },
+            Err(error) => return Err(error.into()),
+        };
+
+        match canonical.starts_with(&self.root) {
+            true => Ok(Some(canonical)),
+            false => Ok(None),
+        }
+    }
+
+    /// Authorize the token stored under `hashed_token` for `permission` over `requested`, consuming the usage it maps to.
+    /// Use `to_blake3()` on a `PrngToken` or `CsprngToken::to_storage_key()` to get the hash
+    pub async fn authorize(&self, hashed_token: &blake3::Hash, requested: &Path, permission: AccessLevel, storage: &dyn Storage) -> Result<SgStatusCode> {
+        let (_, scheme) = match self.check_path(hashed_token, requested, permission, storage).await? {
+            (SgStatusCode::AccessGranted, Some(checked)) => checked,
+            (status, _) => return Ok(status),
+        };
+
+        PrngToken::consume(hashed_token, DirGuard::usage(permission), scheme.as_ref(), storage, ExpiryAction::default()).await
+    }
+
+    /// Authorize like `authorize()` and open the canonical path with options matching `permission`.
+    /// Only `Create`, `Read`, `Write` and `Append` can open a file.
+    /// The last component is opened without following symlinks and the opened handle is checked to be the
+    /// file within the root that was authorized, so swapping in a symlink after the path was resolved is denied.
+    /// The usage of the token is only consumed once the file is open
+    pub async fn open(&self, hashed_token: &blake3::Hash, requested: &Path, permission: AccessLevel, storage: &dyn Storage) -> Result<(SgStatusCode, Option<async_fs::File>)> {
+        let mut options = async_fs::OpenOptions::new();
+        match permission {
+            AccessLevel::Create => options.write(true).create_new(true),
+            AccessLevel::Read => options.read(true),
+            // Truncated once the usage is consumed so a denied token can not empty the file
+            AccessLevel::Write => options.write(true),
+            AccessLevel::Append => options.append(true),
+            AccessLevel::Modify | AccessLevel::Delete => bail!("`{:?}` can not be used to open a file", permission),
+        };
+        #[cfg(unix)]
+        {
+            use async_fs::unix::OpenOptionsExt;
+            options.custom_flags(libc::O_NOFOLLOW);
+        }
+
+        let (canonical, scheme) = match self.check_path(hashed_token, requested, permission, storage).await? {
+            (SgStatusCode::AccessGranted, Some(checked)) => checked,
+            (status, _) => return Ok((status, None)),
+        };
+
+        let file = match options.open(&canonical).await {
+            Ok(file) => file,
+            #[cfg(unix)]
+            Err(error) if error.raw_os_error() == Some(libc::ELOOP) => return Ok((SgStatusCode::AccessDenied, None)),
+            Err(error) => return Err(error.into()),
+        };
+
+        if !self.opened_within(&file, &canonical).await? {
+            if permission == AccessLevel::Create {
+                drop(file);
+                async_fs::remove_file(&canonical).await?;
+            }
+
+            return Ok((SgStatusCode::AccessDenied, None));
+        }
+
+        match PrngToken::consume(hashed_token, DirGuard::usage(permission), scheme.as_ref(), storage, ExpiryAction::default()).await? {
+            SgStatusCode::AccessGranted => {
+                if permission == AccessLevel::Write {
+                    file.set_len(0).await?;
+                }
+
+                Ok((SgStatusCode::AccessGranted, Some(file)))
+            },
+            status => {
+                // Do not leave behind a file the token turned out not to be allowed to create
+                if permission == AccessLevel::Create {
+                    drop(file);
+                    async_fs::remove_file(&canonical).await?;
+                }
+
+                Ok((status, None))
+            },
+        }
+    }
+
+    /// The `Usage` of the token that accessing a file with `permission` counts as
+    pub fn usage(permission: AccessLevel) -> Usage {
+        match permission {
+            AccessLevel::Read => Usage::Download,
+            AccessLevel::Create | AccessLevel::Write | AccessLevel::Append => Usage::Upload,
+            AccessLevel::Modify | AccessLevel::Delete => Usage::Access,
+        }
+    }
+
+    /// Check that `file` is the file at `canonical` and within the root, using the path the kernel reports for the handle
+    #[cfg(target_os = "linux")]
+    async fn opened_within(&self, file: &async_fs::File, canonical: &Path) -> Result<bool> {
+        use std::os::unix::io::AsRawFd;
+
+        let opened = async_fs::read_link(format!("/proc/self/fd/{}", file.as_raw_fd())).await?;
+
+        Ok(opened == canonical && opened.starts_with(&self.root))
+    }
+
+    /// Check that `file` is the file at `canonical` and within the root by comparing device and inode numbers
+    #[cfg(all(unix, not(target_os = "linux")))]
+    async fn opened_within(&self, file: &async_fs::File, canonical: &Path) -> Result<bool> {
+        use std::os::unix::fs::MetadataExt;
+
+        let current = match async_fs::canonicalize(canonical).await {
+            Ok(current) if current.starts_with(&self.root) => current,
+            _ => return Ok(false),
+        };
+
+        let opened = file.metadata().await?;
+        let linked = async_fs::symlink_metadata(&current).await?;
+
+        Ok(opened.dev() == linked.dev() && opened.ino() == linked.ino())
+    }
+
+    /// Opened handles can not be checked on this platform so nothing is opened
+    #[cfg(not(unix))]
+    async fn opened_within(&self, _file: &async_fs::File, _canonical: &Path) -> Result<bool> {
+        Ok(false)
+    }
+
+    /// Resolve the path and check the token and its grants without consuming a usage.
+    /// Returns the canonical path and, for a token bound to a scheme, the `dir:` scheme of the request
+    async fn check_path(&self, hashed_token: &blake3::Hash, requested: &Path, permission: AccessLevel, storage: &dyn Storage) -> Result<(SgStatusCode, Option<(PathBuf, Option<Scheme>)>)> {
+        let canonical = match self.resolve(requested).await? {
+            Some(canonical) => canonical,
+            None => return Ok((SgStatusCode::AccessDenied, None)),
+        };
+
+        let token = match PrngToken::fetch(hashed_token, storage, ExpiryAction::default()).await? {
+            (SgStatusCode::AuthenticToken, Some(token)) => token,
+            (SgStatusCode::Rejected, _) => return Ok((SgStatusCode::AccessDenied, None)),
+            (status, _) => return Ok((status, None)),
+        };
+
+        let scheme = match token.get_scheme() {
+            Some(bound) => {
+                let path = match canonical.to_str() {
+                    Some(path) => path.trim_start_matches('/'),
+                    None => return Ok((SgStatusCode::AccessDenied, None)),
+                };
+                let requested = bound.with_path(path);
+
+                if bound.get_kind() != SchemeKind::Dir || !bound.covers(&requested) {
+                    return Ok((SgStatusCode::AccessDenied, None));
+                }
+
+                Some(requested)
+            },
+            None => None,
+        };
+
+        // Check the path and every directory above it so grants are inherited down the subtree
+        let mut resource = String::new();
+        let mut allowed = token.allows("/", permission);
+        for component in canonical.strip_prefix(&self.root)?.components() {
+            resource.push('/');
+            resource.push_str(&component.as_os_str().to_string_lossy());
+
+            allowed = allowed || token.allows(&resource, permission);
+        }
+
+        if !allowed {
+            return Ok((SgStatusCode::AccessDenied, None));
+        }
+
+        // Spare opening the file for a lease that can not be used this way, `consume()` still has the final say
+        if let LeaseUse::Denied = token.get_lease().next_use(DirGuard::usage(permission)) {
+            return Ok((SgStatusCode::AccessDenied, None));
+        }
+
+        Ok((SgStatusCode::AccessGranted, Some((canonical, scheme))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{global::Lease, Grant, MemPrngStore};
+    use futures_lite::future::block_on;
+
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("schemeguardian-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+
+        dir
+    }
+
+    #[test]
+    fn reads_consume_download_leases() {
+        let root = scratch_dir("download");
+        std::fs::write(root.join("report.txt"), b"report").unwrap();
+
+        let storage = MemPrngStore::new();
+        let token = PrngToken::default()
+            .lease(Lease::OnDownload)
+            .grant(Grant::new("/**", &AccessLevel::all()));
+        let hashed_token = token.token_hash();
+
+        block_on(async {
+            token.store(&hashed_token, &storage).await.unwrap();
+            let guard = DirGuard::new(&root).await.unwrap();
+
+            let (status, _) = guard.open(&hashed_token, Path::new("/report.txt"), AccessLevel::Write, &storage).await.unwrap();
+            assert!(matches!(status, SgStatusCode::AccessDenied));
+            assert_eq!(std::fs::read(root.join("report.txt")).unwrap(), b"report");
+
+            let (status, file) = guard.open(&hashed_token, Path::new("/report.txt"), AccessLevel::Read, &storage).await.unwrap();
+            assert!(matches!(status, SgStatusCode::AccessGranted));
+            assert!(file.is_some());
+
+            let (status, file) = guard.open(&hashed_token, Path::new("/report.txt"), AccessLevel::Read, &storage).await.unwrap();
+            assert!(matches!(status, SgStatusCode::AccessDenied));
+            assert!(file.is_none());
+        });
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn bound_tokens_are_denied_outside_their_directory() {
+        let bound_root = scratch_dir("bound-a");
+        let other_root = scratch_dir("bound-b");
+        std::fs::write(bound_root.join("notes.txt"), b"a").unwrap();
+        std::fs::write(other_root.join("notes.txt"), b"b").unwrap();
+
+        let storage = MemPrngStore::new();
+        let bound_path = std::fs::canonicalize(&bound_root).unwrap();
+        let scheme = format!("dir:localhost:1/{}", bound_path.to_str().unwrap().trim_start_matches('/'))
+            .parse::<Scheme>()
+            .unwrap();
+        let token = PrngToken::default()
+            .lease(Lease::Lifetime)
+            .scheme(scheme)
+            .grant(Grant::new("/**", &AccessLevel::all()));
+        let hashed_token = token.token_hash();
+
+        block_on(async {
+            token.store(&hashed_token, &storage).await.unwrap();
+
+            let other = DirGuard::new(&other_root).await.unwrap();
+            let (status, file) = other.open(&hashed_token, Path::new("/notes.txt"), AccessLevel::Read, &storage).await.unwrap();
+            assert!(matches!(status, SgStatusCode::AccessDenied));
+            assert!(file.is_none());
+            assert!(matches!(other.authorize(&hashed_token, Path::new("/notes.txt"), AccessLevel::Read, &storage).await.unwrap(), SgStatusCode::AccessDenied));
+
+            let bound = DirGuard::new(&bound_root).await.unwrap();
+            let (status, file) = bound.open(&hashed_token, Path::new("/notes.txt"), AccessLevel::Read, &storage).await.unwrap();
+            assert!(matches!(status, SgStatusCode::AccessGranted));
+            assert!(file.is_some());
+        });
+
+        std::fs::remove_dir_all(&bound_root).unwrap();
+        std::fs::remove_dir_all(&other_root).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlinks_out_of_the_root_are_denied() {
+        let root = scratch_dir("symlink");
+        let outside = scratch_dir("outside");
+        std::fs::write(outside.join("secret.txt"), b"secret").unwrap();
+        std::os::unix::fs::symlink(outside.join("secret.txt"), root.join("link.txt")).unwrap();
+
+        let storage = MemPrngStore::new();
+        let token = PrngToken::default()
+            .lease(Lease::Lifetime)
+            .grant(Grant::new("/**", &AccessLevel::all()));
+        let hashed_token = token.token_hash();
+
+        block_on(async {
+            token.store(&hashed_token, &storage).await.unwrap();
+            let guard = DirGuard::new(&root).await.unwrap();
+
+            let (status, file) = guard.open(&hashed_token, Path::new("/link.txt"), AccessLevel::Read, &storage).await.unwrap();
+            assert!(matches!(status, SgStatusCode::AccessDenied));
+            assert!(file.is_none());
+        });
+
+        std::fs::remove_dir_all(&root).unwrap();
+        std::fs::remove_dir_all(&outside).unwrap();
+    }
+}
@@ -0,0 +1,156 @@
+use serde::{Serialize, Deserialize};
+
+/// ### Access level of a secret over a resource
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum AccessLevel {
+    /// Create a new resource
+    Create,
+    /// Read a resource
+    Read,
+    /// Replace the contents of a resource
+    Write,
+    /// Add to the end of a resource without changing what is already there
+    Append,
+    /// Change the metadata of a resource, like renaming it
+    Modify,
+    /// Delete a resource
+    Delete,
+}
+
+impl AccessLevel {
+    /// Every access level, useful to grant full access over a resource
+    pub fn all() -> Vec<AccessLevel> {
+        vec![
+            AccessLevel::Create,
+            AccessLevel::Read,
+            AccessLevel::Write,
+            AccessLevel::Append,
+            AccessLevel::Modify,
+            AccessLevel::Delete,
+        ]
+    }
+}
+
+/// ### A set of `AccessLevel`s over the resources matching a pattern
+/// Resource patterns are `/` separated paths where `*` matches any characters within a segment,
+/// `?` matches a single character within a segment and `**` matches any number of segments.
+/// A resource with a `.` or `..` segment is never allowed, it could resolve outside the pattern.
+/// #### Example
+/// ```
+/// use schemeguardian::{Grant, AccessLevel};
+/// let grant = Grant::new("/files/**/*.txt", &[AccessLevel::Read]);
+/// assert!(grant.allows("/files/reports/2020/summary.txt", AccessLevel::Read));
+/// assert!(!grant.allows("/files/reports/summary.txt", AccessLevel::Delete));
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Grant {
+    resource: String,
+    permissions: Vec<AccessLevel>,
+}
+
+impl Grant {
+    pub fn new(resource: &str, permissions: &[AccessLevel]) -> Self {
+        Self {
+            resource: resource.into(),
+            permissions: permissions.to_vec(),
+        }
+    }
+
+    pub fn get_resource(&self) -> &str {
+        &self.resource
+    }
+
+    pub fn get_permissions(&self) -> &[AccessLevel] {
+        &self.permissions
+    }
+
+    /// Check whether this grant allows `permission` over `resource`
+    pub fn allows(&self, resource: &str, permission: AccessLevel) -> bool {
+        self.permissions.contains(&permission) && glob_match(&self.resource, resource)
+    }
+}
+
+/// Match a `/` separated `resource` against a glob `pattern`.
+/// A `resource` with a `.` or `..` segment never matches
+pub fn glob_match(pattern: &str, resource: &str) -> bool {
+    let pattern = pattern.split('/').filter(|segment| !segment.is_empty()).collect::<Vec<&str>>();
+    let resource = resource.split('/').filter(|segment| !segment.is_empty()).collect::<Vec<&str>>();
+
+    if resource.iter().any(|segment| *segment == "." || *segment == "..") {
+        return false;
+    }
+
+    match_segments(&pattern, &resource)
+}
+
+fn match_segments(pattern: &[&str], resource: &[&str]) -> bool {
+    match pattern.split_first() {
+        None => resource.is_empty(),
+        Some((&"**", rest)) => {
+            (0..=resource.len()).any(|skipped| match_segments(rest, &resource[skipped..]))
+        },
+        Some((segment, rest)) => match resource.split_first() {
+            Some((name, remaining)) => match_segment(segment.as_bytes(), name.as_bytes()) && match_segments(rest, remaining),
+            None => false,
+        },
+    }
+}
+
+fn match_segment(pattern: &[u8], name: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => name.is_empty(),
+        Some((b'*', rest)) => (0..=name.len()).any(|skipped| match_segment(rest, &name[skipped..])),
+        Some((b'?', rest)) => !name.is_empty() && match_segment(rest, &name[1..]),
+        Some((expected, rest)) => name.first() == Some(expected) && match_segment(rest, &name[1..]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn traversal_segments_never_match() {
+        assert!(!glob_match("/files/**", "/files/../etc/passwd"));
+        assert!(!glob_match("/files/**", "/files/./report.txt"));
+        assert!(!glob_match("/files/*/*", "/files/../passwd"));
+        assert!(!glob_match("/**", "/.."));
+
+        // Dots inside a segment are not traversal
+        assert!(glob_match("/files/**", "/files/..hidden/report.txt"));
+        assert!(glob_match("/files/*", "/files/report.tar.gz"));
+    }
+
+    #[test]
+    fn star_matches_within_a_segment() {
+        assert!(glob_match("/files/*.txt", "/files/report.txt"));
+        assert!(glob_match("/files/*", "/files/report.txt"));
+        assert!(!glob_match("/files/*.txt", "/files/reports/summary.txt"));
+        assert!(!glob_match("/files/*.txt", "/files/report.csv"));
+    }
+
+    #[test]
+    fn question_mark_matches_a_single_character() {
+        assert!(glob_match("/logs/day-?.log", "/logs/day-1.log"));
+        assert!(!glob_match("/logs/day-?.log", "/logs/day-10.log"));
+        assert!(!glob_match("/logs/day-?.log", "/logs/day-.log"));
+    }
+
+    #[test]
+    fn double_star_matches_any_number_of_segments() {
+        assert!(glob_match("/files/**", "/files"));
+        assert!(glob_match("/files/**", "/files/a/b/c"));
+        assert!(glob_match("/files/**/*.txt", "/files/summary.txt"));
+        assert!(glob_match("/files/**/*.txt", "/files/reports/2020/summary.txt"));
+        assert!(!glob_match("/files/**/*.txt", "/other/summary.txt"));
+    }
+
+    #[test]
+    fn grants_check_the_permission_and_the_resource() {
+        let grant = Grant::new("/files/**", &[AccessLevel::Read]);
+
+        assert!(grant.allows("/files/report.txt", AccessLevel::Read));
+        assert!(!grant.allows("/files/report.txt", AccessLevel::Write));
+        assert!(!grant.allows("/files/../etc/passwd", AccessLevel::Read));
+    }
+}
@@ -0,0 +1,11 @@
+mod roles;
+mod grants;
+mod scheme;
+mod dir_guard;
+mod port_guard;
+
+pub use roles::*;
+pub use grants::*;
+pub use scheme::*;
+pub use dir_guard::*;
+pub use port_guard::*;
@@ -0,0 +1,118 @@
+use crate::{global::SgStatusCode, Scheme, SchemeKind, SgConfig};
+
+use anyhow::{Result, bail};
+use std::collections::HashMap;
+
+/// Ports that only ever accept one scheme.
+/// These are compiled in so they hold even if the configuration is misconfigured or changed maliciously
+pub const PORT_INVARIANTS: &[(u16, SchemeKind)] = &[
+    (443, SchemeKind::Https),
+    (80, SchemeKind::Http),
+    (21, SchemeKind::Ftp),
+];
+
+/// ### Pins schemes to the ports they are expected on
+/// A port listed in `PORT_INVARIANTS` or in the `[[ports]]` of the configuration only accepts
+/// the schemes listed for it, any other port accepts every scheme
+#[derive(Debug, Clone)]
+pub struct PortGuard {
+    ports: HashMap<u16, Vec<SchemeKind>>,
+}
+
+impl Default for PortGuard {
+    fn default() -> Self {
+        let ports = PORT_INVARIANTS.iter()
+            .map(|(port, kind)| (*port, vec![*kind]))
+            .collect();
+
+        Self { ports }
+    }
+}
+
+impl PortGuard {
+    /// The invariants together with the `[[ports]]` of the configuration.
+    /// A configuration that allows other schemes on an invariant port is an error
+    pub fn from_config(config: &SgConfig) -> Result<Self> {
+        let mut guard = PortGuard::default();
+
+        for entry in &config.ports {
+            if entry.schemes.is_empty() {
+                bail!("Port `{}` must allow at least one scheme", entry.port);
+            }
+
+            if let Some((_, expected)) = PORT_INVARIANTS.iter().find(|(port, _)| *port == entry.port) {
+                if let Some(violation) = entry.schemes.iter().find(|kind| *kind != expected) {
+                    bail!("Port `{}` only accepts `{}` but the configuration allows `{}`", entry.port, expected, violation);
+                }
+
+                continue;
+            }
+
+            if guard.ports.insert(entry.port, entry.schemes.clone()).is_some() {
+                bail!("Port `{}` is configured more than once", entry.port);
+            }
+        }
+
+        Ok(guard)
+    }
+
+    /// Check whether `scheme` is allowed on its port
+    pub fn allows(&self, scheme: &Scheme) -> bool {
+        match self.ports.get(&scheme.get_port()) {
+            Some(allowed) => allowed.contains(&scheme.get_kind()),
+            None => true,
+        }
+    }
+
+    /// `SgStatusCode::AccessGranted` if `scheme` is allowed on its port, `SgStatusCode::AccessDenied` otherwise
+    pub fn check(&self, scheme: &Scheme) -> SgStatusCode {
+        match self.allows(scheme) {
+            true => SgStatusCode::AccessGranted,
+            false => SgStatusCode::AccessDenied,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::PortConfig;
+
+    fn config(port: u16, schemes: &[SchemeKind]) -> SgConfig {
+        let mut config = SgConfig::default();
+        config.ports.push(PortConfig { port, schemes: schemes.to_vec() });
+
+        config
+    }
+
+    fn check(guard: &PortGuard, scheme: &str) -> SgStatusCode {
+        guard.check(&scheme.parse::<Scheme>().unwrap())
+    }
+
+    #[test]
+    fn ports_only_accept_their_schemes() {
+        let guard = PortGuard::from_config(&config(8443, &[SchemeKind::Https, SchemeKind::Cookie])).unwrap();
+
+        assert!(matches!(check(&guard, "https:example.com:443/"), SgStatusCode::AccessGranted));
+        assert!(matches!(check(&guard, "http:example.com:443/"), SgStatusCode::AccessDenied));
+        assert!(matches!(check(&guard, "ftp:example.com:21/"), SgStatusCode::AccessGranted));
+        assert!(matches!(check(&guard, "tcp:example.com:21/"), SgStatusCode::AccessDenied));
+
+        assert!(matches!(check(&guard, "cookie:example.com:8443/session"), SgStatusCode::AccessGranted));
+        assert!(matches!(check(&guard, "http:example.com:8443/"), SgStatusCode::AccessDenied));
+
+        assert!(matches!(check(&guard, "http:example.com:8080/"), SgStatusCode::AccessGranted));
+    }
+
+    #[test]
+    fn configurations_can_not_override_the_invariants() {
+        assert!(PortGuard::from_config(&config(443, &[SchemeKind::Http])).is_err());
+        assert!(PortGuard::from_config(&config(443, &[SchemeKind::Https, SchemeKind::Http])).is_err());
+        assert!(PortGuard::from_config(&config(443, &[SchemeKind::Https])).is_ok());
+        assert!(PortGuard::from_config(&config(8443, &[])).is_err());
+
+        let mut twice = config(8443, &[SchemeKind::Https]);
+        twice.ports.push(PortConfig { port: 8443, schemes: vec![SchemeKind::Http] });
+        assert!(PortGuard::from_config(&twice).is_err());
+    }
+}
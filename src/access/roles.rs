@@ -0,0 +1,162 @@
+use crate::{global::Role, SgConfig};
+
+use anyhow::{Result, bail};
+use std::collections::HashMap;
+
+/// ### Which roles inherit the permissions of which other roles
+/// By default `SuperUser` inherits `Admin`, `Admin` inherits `SubAdmin` and `SubAdmin` inherits `User`.
+/// Custom `Role::Specifed` roles are slotted in with `RoleHierarchy::insert()` or from the `[[roles]]`
+/// of the configuration, a role may inherit several roles as long as no role ends up inheriting itself.
+/// Roles are compared as `Role` values and not by name, so a `Role::Specifed` named after a builtin role
+/// never gets the permissions of that builtin role
+#[derive(Debug, Clone)]
+pub struct RoleHierarchy {
+    inherits: HashMap<Role, Vec<Role>>,
+}
+
+impl Default for RoleHierarchy {
+    fn default() -> Self {
+        let mut inherits = HashMap::new();
+        inherits.insert(Role::SuperUser, vec![Role::Admin]);
+        inherits.insert(Role::Admin, vec![Role::SubAdmin]);
+        inherits.insert(Role::SubAdmin, vec![Role::User]);
+        inherits.insert(Role::User, Vec::new());
+
+        Self { inherits }
+    }
+}
+
+impl RoleHierarchy {
+    /// The default hierarchy with the custom roles of the configuration slotted in
+    pub fn from_config(config: &SgConfig) -> Result<Self> {
+        let mut hierarchy = RoleHierarchy::default();
+
+        // Register every role first so roles can refer to roles defined after them
+        for role in &config.roles {
+            let role = Role::from_name(&role.name);
+            RoleHierarchy::ensure_distinct(&role)?;
+
+            hierarchy.inherits.entry(role).or_insert_with(Vec::new);
+        }
+
+        for role in &config.roles {
+            hierarchy.insert(
+                &Role::from_name(&role.name),
+                role.inherits.as_ref().map(|name| Role::from_name(name)).as_ref(),
+                role.inherited_by.as_ref().map(|name| Role::from_name(name)).as_ref(),
+            )?;
+        }
+
+        Ok(hierarchy)
+    }
+
+    /// Add `role` so that it inherits the permissions of `inherits` and
+    /// its own permissions are inherited by `inherited_by`.
+    /// A `Role::Specifed` named after a builtin role is rejected
+    pub fn insert(&mut self, role: &Role, inherits: Option<&Role>, inherited_by: Option<&Role>) -> Result<()> {
+        RoleHierarchy::ensure_distinct(role)?;
+
+        for related in inherits.iter().chain(inherited_by.iter()) {
+            RoleHierarchy::ensure_distinct(related)?;
+
+            if !self.inherits.contains_key(*related) {
+                bail!("Role `{}` refers to the unknown role `{}`", role.name(), related.name());
+            }
+        }
+
+        let mut updated = self.inherits.clone();
+        let entry = updated.entry(role.clone()).or_insert_with(Vec::new);
+        if let Some(lower) = inherits {
+            entry.push(lower.clone());
+        }
+        if let Some(higher) = inherited_by {
+            if let Some(children) = updated.get_mut(higher) {
+                children.push(role.clone());
+            }
+        }
+
+        let candidate = RoleHierarchy { inherits: updated };
+        if candidate.inherits.get(role).map_or(false, |lower| {
+            lower.iter().any(|inherited| candidate.reaches(inherited, role))
+        }) {
+            bail!("Role `{}` would inherit its own permissions", role.name());
+        }
+
+        *self = candidate;
+
+        Ok(())
+    }
+
+    /// Check whether a token holding `held` has the permissions of `required`
+    pub fn satisfies(&self, held: &Role, required: &Role) -> bool {
+        self.reaches(held, required)
+    }
+
+    fn ensure_distinct(role: &Role) -> Result<()> {
+        if let Role::Specifed(name) = role {
+            if Role::from_name(name).is_builtin() {
+                bail!("The custom role `{}` has the name of a builtin role", name);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn reaches(&self, from: &Role, to: &Role) -> bool {
+        let mut pending = vec![from];
+        let mut visited = Vec::new();
+
+        while let Some(current) = pending.pop() {
+            if current == to {
+                return true;
+            }
+            if visited.contains(&current) {
+                continue;
+            }
+            visited.push(current);
+
+            if let Some(lower) = self.inherits.get(current) {
+                pending.extend(lower.iter());
+            }
+        }
+
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builtin_roles_inherit_downwards() {
+        let hierarchy = RoleHierarchy::default();
+
+        assert!(hierarchy.satisfies(&Role::SuperUser, &Role::User));
+        assert!(hierarchy.satisfies(&Role::Admin, &Role::SubAdmin));
+        assert!(!hierarchy.satisfies(&Role::User, &Role::Admin));
+    }
+
+    #[test]
+    fn a_custom_role_named_after_a_builtin_has_none_of_its_permissions() {
+        let mut hierarchy = RoleHierarchy::default();
+        let impostor = Role::Specifed("SuperUser".into());
+
+        assert!(!hierarchy.satisfies(&impostor, &Role::SuperUser));
+        assert!(!hierarchy.satisfies(&impostor, &Role::User));
+        assert!(hierarchy.insert(&impostor, Some(&Role::User), None).is_err());
+    }
+
+    #[test]
+    fn custom_roles_are_slotted_in_without_cycles() {
+        let mut hierarchy = RoleHierarchy::default();
+        let auditor = Role::Specifed("Auditor".into());
+
+        hierarchy.insert(&auditor, Some(&Role::User), Some(&Role::SubAdmin)).unwrap();
+        assert!(hierarchy.satisfies(&Role::Admin, &auditor));
+        assert!(hierarchy.satisfies(&auditor, &Role::User));
+        assert!(!hierarchy.satisfies(&auditor, &Role::SubAdmin));
+
+        assert!(hierarchy.insert(&Role::User, Some(&auditor), None).is_err());
+    }
+}
@@ -0,0 +1,296 @@
+use serde::{Serialize, Deserialize};
+use std::{fmt, str::FromStr};
+
+/// ### The kind of a `Scheme`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SchemeKind {
+    /// `https:`
+    Https,
+    /// `http:`
+    Http,
+    /// `cookie:`
+    Cookie,
+    /// `dir:` a directory guard
+    Dir,
+    /// `perm:` a permission guard
+    Perm,
+    /// `db:` a database
+    Db,
+    /// `tcp:` a TCP address
+    Tcp,
+    /// `udp:` a UDP address
+    Udp,
+    /// `ftp:` an FTP address
+    Ftp,
+}
+
+impl SchemeKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SchemeKind::Https => "https",
+            SchemeKind::Http => "http",
+            SchemeKind::Cookie => "cookie",
+            SchemeKind::Dir => "dir",
+            SchemeKind::Perm => "perm",
+            SchemeKind::Db => "db",
+            SchemeKind::Tcp => "tcp",
+            SchemeKind::Udp => "udp",
+            SchemeKind::Ftp => "ftp",
+        }
+    }
+}
+
+impl FromStr for SchemeKind {
+    type Err = SchemeError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "https" => Ok(SchemeKind::Https),
+            "http" => Ok(SchemeKind::Http),
+            "cookie" => Ok(SchemeKind::Cookie),
+            "dir" => Ok(SchemeKind::Dir),
+            "perm" => Ok(SchemeKind::Perm),
+            "db" => Ok(SchemeKind::Db),
+            "tcp" => Ok(SchemeKind::Tcp),
+            "udp" => Ok(SchemeKind::Udp),
+            "ftp" => Ok(SchemeKind::Ftp),
+            "" => Err(SchemeError::MissingScheme),
+            unknown => Err(SchemeError::UnknownScheme(unknown.into())),
+        }
+    }
+}
+
+impl fmt::Display for SchemeKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// ### Errors from parsing a `Scheme`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SchemeError {
+    /// The scheme before the first `:` is missing
+    MissingScheme,
+    /// The scheme is not one of the `SchemeKind`s
+    UnknownScheme(String),
+    /// The `:` separated url is missing
+    MissingUrl,
+    /// The url contains characters that are not allowed
+    InvalidUrl(String),
+    /// The `:` separated port is missing
+    MissingPort,
+    /// The port is not a number between 1 and 65535 without leading zeros
+    InvalidPort(String),
+    /// The `/` after the port is missing
+    MissingPath,
+    /// The scheme contains whitespace or control characters
+    InvalidCharacter(usize),
+}
+
+impl fmt::Display for SchemeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SchemeError::MissingScheme => write!(f, "expected `[scheme]:[url]:[port]/[query_string]` but the scheme is missing"),
+            SchemeError::UnknownScheme(scheme) => write!(f, "unknown scheme `{}`", scheme),
+            SchemeError::MissingUrl => write!(f, "the url after `[scheme]:` is missing"),
+            SchemeError::InvalidUrl(url) => write!(f, "invalid url `{}`", url),
+            SchemeError::MissingPort => write!(f, "the port after `[scheme]:[url]:` is missing"),
+            SchemeError::InvalidPort(port) => write!(f, "invalid port `{}`, expected a number between 1 and 65535", port),
+            SchemeError::MissingPath => write!(f, "the `/` after the port is missing"),
+            SchemeError::InvalidCharacter(index) => write!(f, "whitespace or control character at byte {}", index),
+        }
+    }
+}
+
+impl std::error::Error for SchemeError {}
+
+/// ### A guarded scheme in the form `[scheme]:[url]:[port]/[query_string]`
+/// #### Example
+/// ```
+/// use schemeguardian::{Scheme, SchemeKind};
+/// let scheme: Scheme = "cookie:0000:3030/identifier?cookie=nnmlblblkglguk".parse().unwrap();
+/// assert_eq!(scheme.get_kind(), SchemeKind::Cookie);
+/// assert_eq!(scheme.get_port(), 3030);
+/// assert_eq!(scheme.to_string(), "cookie:0000:3030/identifier?cookie=nnmlblblkglguk");
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Scheme {
+    kind: SchemeKind,
+    url: String,
+    port: u16,
+    path: String,
+    query: Option<String>,
+}
+
+impl Scheme {
+    pub fn get_kind(&self) -> SchemeKind {
+        self.kind
+    }
+
+    pub fn get_url(&self) -> &str {
+        &self.url
+    }
+
+    pub fn get_port(&self) -> u16 {
+        self.port
+    }
+
+    /// The path after the port without the leading `/`
+    pub fn get_path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn get_query(&self) -> Option<&str> {
+        self.query.as_deref()
+    }
+
+    /// The same kind, url and port with another path and no query, `path` is given without the leading `/`
+    pub (crate) fn with_path(&self, path: &str) -> Scheme {
+        Scheme {
+            kind: self.kind,
+            url: self.url.clone(),
+            port: self.port,
+            path: path.into(),
+            query: None,
+        }
+    }
+
+    /// Check whether a token bound to this scheme may be used for `requested`.
+    /// The kind, url and port must be the same and the requested path must be within the bound path.
+    /// A requested path with a `.` or `..` segment is never covered, it could resolve outside the bound path
+    pub fn covers(&self, requested: &Scheme) -> bool {
+        if self.kind != requested.kind || self.url != requested.url || self.port != requested.port {
+            return false;
+        }
+
+        if requested.path.split('/').any(|segment| segment == "." || segment == "..") {
+            return false;
+        }
+
+        let mut wanted = requested.path.split('/').filter(|segment| !segment.is_empty());
+
+        self.path.split('/')
+            .filter(|segment| !segment.is_empty())
+            .all(|segment| wanted.next() == Some(segment))
+    }
+}
+
+impl FromStr for Scheme {
+    type Err = SchemeError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        if let Some(index) = value.find(|character: char| character.is_whitespace() || character.is_control()) {
+            return Err(SchemeError::InvalidCharacter(index));
+        }
+
+        let mut parts = value.splitn(3, ':');
+        let kind = parts.next().unwrap_or_default().parse::<SchemeKind>()?;
+
+        let url = match parts.next() {
+            Some("") | None => return Err(SchemeError::MissingUrl),
+            Some(url) if url.contains('/') || url.contains('?') => return Err(SchemeError::InvalidUrl(url.into())),
+            Some(url) => url,
+        };
+
+        let rest = match parts.next() {
+            Some(rest) => rest,
+            None => return Err(SchemeError::MissingPort),
+        };
+
+        let (port, target) = match rest.find('/') {
+            Some(index) => (&rest[..index], &rest[index + 1..]),
+            None if rest.is_empty() => return Err(SchemeError::MissingPort),
+            None => return Err(SchemeError::MissingPath),
+        };
+
+        if port.is_empty() {
+            return Err(SchemeError::MissingPort);
+        }
+        // A leading zero would let two spellings of a port bind the same scheme
+        if port.len() > 1 && port.starts_with('0') {
+            return Err(SchemeError::InvalidPort(port.into()));
+        }
+        let port = match port.parse::<u16>() {
+            Ok(number) if number > 0 && port.bytes().all(|digit| digit.is_ascii_digit()) => number,
+            _ => return Err(SchemeError::InvalidPort(port.into())),
+        };
+
+        let (path, query) = match target.find('?') {
+            Some(index) => (&target[..index], Some(target[index + 1..].to_owned())),
+            None => (target, None),
+        };
+
+        Ok(Scheme {
+            kind,
+            url: url.into(),
+            port,
+            path: path.into(),
+            query,
+        })
+    }
+}
+
+impl fmt::Display for Scheme {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}:{}/{}", self.kind, self.url, self.port, self.path)?;
+
+        match &self.query {
+            Some(query) => write!(f, "?{}", query),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn display_round_trips_what_was_parsed() {
+        for value in &[
+            "https:example.com:443/",
+            "cookie:0000:3030/identifier?cookie=nnmlblblkglguk",
+            "dir:localhost:1/srv/files",
+            "tcp:10.0.0.1:65535/?",
+        ] {
+            let scheme = value.parse::<Scheme>().unwrap();
+
+            assert_eq!(&scheme.to_string(), value);
+            assert_eq!(scheme.to_string().parse::<Scheme>().unwrap(), scheme);
+        }
+    }
+
+    #[test]
+    fn ports_must_be_canonical() {
+        assert_eq!("https:example.com:0443/".parse::<Scheme>(), Err(SchemeError::InvalidPort("0443".into())));
+        assert_eq!("https:example.com:0000/".parse::<Scheme>(), Err(SchemeError::InvalidPort("0000".into())));
+        assert_eq!("https:example.com:0/".parse::<Scheme>(), Err(SchemeError::InvalidPort("0".into())));
+        assert_eq!("https:example.com:65536/".parse::<Scheme>(), Err(SchemeError::InvalidPort("65536".into())));
+        assert_eq!("https:example.com:+443/".parse::<Scheme>(), Err(SchemeError::InvalidPort("+443".into())));
+        assert_eq!("https:example.com:/".parse::<Scheme>(), Err(SchemeError::MissingPort));
+    }
+
+    #[test]
+    fn malformed_schemes_are_rejected() {
+        assert_eq!(":example.com:443/".parse::<Scheme>(), Err(SchemeError::MissingScheme));
+        assert_eq!("gopher:example.com:70/".parse::<Scheme>(), Err(SchemeError::UnknownScheme("gopher".into())));
+        assert_eq!("https::443/".parse::<Scheme>(), Err(SchemeError::MissingUrl));
+        assert_eq!("https:example.com:443".parse::<Scheme>(), Err(SchemeError::MissingPath));
+        assert_eq!("https:example.com:443/a b".parse::<Scheme>(), Err(SchemeError::InvalidCharacter(23)));
+    }
+
+    #[test]
+    fn covers_stays_within_the_bound_path() {
+        let bound = "https:example.com:443/api".parse::<Scheme>().unwrap();
+        let parse = |value: &str| value.parse::<Scheme>().unwrap();
+
+        assert!(bound.covers(&parse("https:example.com:443/api")));
+        assert!(bound.covers(&parse("https:example.com:443/api/users?page=2")));
+        assert!(!bound.covers(&parse("https:example.com:443/apis")));
+        assert!(!bound.covers(&parse("https:example.com:443/api/../admin")));
+        assert!(!bound.covers(&parse("https:example.com:443/api/./users")));
+        assert!(!bound.covers(&parse("http:example.com:443/api")));
+        assert!(!bound.covers(&parse("https:example.com:8443/api")));
+    }
+}
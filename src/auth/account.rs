@@ -0,0 +1,249 @@
+use crate::{global::{
+    SgStatusCode,
+    Identifier,
+    Role,
+    TaiTimestamp,
+    ACCOUNT_DOCUMENT,
+    PASSPHRASE_DOCUMENT,
+}, storage::Storage, Argon2Config, Passphrase, TempLock};
+
+use secrecy::{Secret, ExposeSecret};
+use anyhow::Result;
+use serde::{Serialize, Deserialize};
+
+/// ### The current status of the authentication mechanism of an account
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AuthState {
+    /// The account is in a default state with a randomly generated authentication mechanism,
+    /// for example an account that was pre-registered and still has to set its credentials
+    RandomDefault,
+    /// The user triggered a reset of the authentication mechanism
+    ResetTriggered,
+    /// The account is in its normal state and visible to the user
+    Transparent,
+    /// The reset code has been confirmed and the user has to set the new authentication mechanism
+    ResetInProgress,
+    /// The account is temporarily locked until the `TempLock` is lifted
+    TempLock(TempLock),
+}
+
+impl AuthState {
+    /// Check whether moving from this state to `next` is allowed.
+    /// A reset always has to be confirmed, going through `ResetInProgress`, before the account becomes `Transparent`
+    pub fn can_transition(&self, next: &AuthState) -> bool {
+        match (self, next) {
+            (AuthState::RandomDefault, AuthState::ResetInProgress) => true,
+            (AuthState::Transparent, AuthState::ResetTriggered) => true,
+            (AuthState::ResetTriggered, AuthState::ResetInProgress) => true,
+            (AuthState::ResetInProgress, AuthState::Transparent) => true,
+            (AuthState::Transparent, AuthState::TempLock(_)) => true,
+            (AuthState::TempLock(_), AuthState::Transparent) => true,
+            (AuthState::TempLock(_), AuthState::ResetTriggered) => true,
+            _ => false,
+        }
+    }
+}
+
+/// ### An account and the state of its authentication mechanism
+/// Stored under the `Identifier::storage_key()` of the account in the `ACCOUNT_DOCUMENT`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Account {
+    identifier: Secret<Identifier>,
+    role: Secret<Role>,
+    state: AuthState,
+    last_changed: Secret<TaiTimestamp>,
+}
+
+impl Account {
+    /// Pre-register an account in the `AuthState::RandomDefault` state
+    pub async fn create(identifier: &Identifier, role: Role, storage: &dyn Storage) -> Result<SgStatusCode> {
+        let account = Account {
+            identifier: Secret::new(identifier.clone()),
+            role: Secret::new(role),
+            state: AuthState::RandomDefault,
+            last_changed: Secret::new(TaiTimestamp::now()),
+        };
+        let data = bincode::serialize::<Account>(&account)?;
+
+        match storage.compare_and_swap(ACCOUNT_DOCUMENT, identifier.storage_key().as_bytes(), None, Some(&data)).await? {
+            true => Ok(SgStatusCode::StateChanged),
+            false => Ok(SgStatusCode::AlreadyRegistered),
+        }
+    }
+
+    /// Get the account of an identifier
+    pub async fn load(identifier: &Identifier, storage: &dyn Storage) -> Result<Option<Account>> {
+        match storage.get(ACCOUNT_DOCUMENT, identifier.storage_key().as_bytes()).await? {
+            Some(data) => Ok(Some(bincode::deserialize::<Account>(&data)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Remove the account of an identifier
+    pub async fn remove(identifier: &Identifier, storage: &dyn Storage) -> Result<SgStatusCode> {
+        match storage.delete(ACCOUNT_DOCUMENT, identifier.storage_key().as_bytes()).await? {
+            true => Ok(SgStatusCode::Revoked),
+            false => Ok(SgStatusCode::Rejected),
+        }
+    }
+
+    /// Move the account to the `next` state.
+    /// Returns `SgStatusCode::TransitionDenied` if `AuthState::can_transition` does not allow it
+    /// and `SgStatusCode::Rejected` if the account does not exist
+    pub async fn transition(identifier: &Identifier, next: AuthState, storage: &dyn Storage) -> Result<SgStatusCode> {
+        Account::transition_from(identifier, None, next, storage).await
+    }
+
+    /// `transition` that is also denied unless the stored state is `current`, checked in the same compare-and-swap
+    async fn transition_from(identifier: &Identifier, current: Option<&AuthState>, next: AuthState, storage: &dyn Storage) -> Result<SgStatusCode> {
+        let key = identifier.storage_key();
+
+        loop {
+            let data = match storage.get(ACCOUNT_DOCUMENT, key.as_bytes()).await? {
+                Some(data) => data,
+                None => return Ok(SgStatusCode::Rejected),
+            };
+
+            let mut account = match bincode::deserialize::<Account>(&data) {
+                Ok(account) => account,
+                Err(_) => return Ok(SgStatusCode::BadDeserialize),
+            };
+
+            if current.map_or(false, |current| &account.state != current) || !account.state.can_transition(&next) {
+                return Ok(SgStatusCode::TransitionDenied);
+            }
+
+            account.state = next.clone();
+            account.last_changed = Secret::new(TaiTimestamp::now());
+            let updated = bincode::serialize::<Account>(&account)?;
+
+            if storage.compare_and_swap(ACCOUNT_DOCUMENT, key.as_bytes(), Some(&data), Some(&updated)).await? {
+                return Ok(SgStatusCode::StateChanged);
+            }
+        }
+    }
+
+    /// The user asks to reset the authentication mechanism
+    pub async fn trigger_reset(identifier: &Identifier, storage: &dyn Storage) -> Result<SgStatusCode> {
+        Account::transition(identifier, AuthState::ResetTriggered, storage).await
+    }
+
+    /// The reset code sent to the user, or to a pre-registered account, has been confirmed
+    pub async fn confirm_reset(identifier: &Identifier, storage: &dyn Storage) -> Result<SgStatusCode> {
+        Account::transition(identifier, AuthState::ResetInProgress, storage).await
+    }
+
+    /// Set the passphrase of an account whose reset is in progress, making the account `AuthState::Transparent`.
+    /// The passphrase is only written by the caller whose compare-and-swap ended the reset, so it can not
+    /// replace the credentials of an account that is no longer resetting
+    pub async fn set_credentials(identifier: &Identifier, passphrase: &Passphrase, storage: &dyn Storage, config: &Argon2Config) -> Result<SgStatusCode> {
+        // Hashing first so a failure leaves the reset in progress
+        let phc = passphrase.hash(config)?;
+
+        match Account::transition_from(identifier, Some(&AuthState::ResetInProgress), AuthState::Transparent, storage).await? {
+            SgStatusCode::StateChanged => {
+                storage.put(PASSPHRASE_DOCUMENT, identifier.storage_key().as_bytes(), phc.as_bytes()).await?;
+
+                Ok(SgStatusCode::StateChanged)
+            },
+            status => Ok(status),
+        }
+    }
+
+    pub fn get_identifier(&self) -> &Identifier {
+        self.identifier.expose_secret()
+    }
+
+    pub fn get_role(&self) -> &Role {
+        self.role.expose_secret()
+    }
+
+    pub fn get_state(&self) -> &AuthState {
+        &self.state
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MemPrngStore;
+    use futures_lite::future::block_on;
+    use secrecy::SecretString;
+
+    fn locked() -> AuthState {
+        AuthState::TempLock(TempLock::RandomToMail)
+    }
+
+    #[test]
+    fn a_reset_has_to_be_confirmed() {
+        assert!(AuthState::Transparent.can_transition(&AuthState::ResetTriggered));
+        assert!(AuthState::ResetTriggered.can_transition(&AuthState::ResetInProgress));
+        assert!(AuthState::ResetInProgress.can_transition(&AuthState::Transparent));
+
+        assert!(!AuthState::ResetTriggered.can_transition(&AuthState::Transparent));
+        assert!(!AuthState::RandomDefault.can_transition(&AuthState::Transparent));
+    }
+
+    #[test]
+    fn a_lock_can_not_skip_a_pending_reset() {
+        assert!(AuthState::Transparent.can_transition(&locked()));
+        assert!(locked().can_transition(&AuthState::Transparent));
+        assert!(locked().can_transition(&AuthState::ResetTriggered));
+
+        assert!(!AuthState::RandomDefault.can_transition(&locked()));
+        assert!(!AuthState::ResetTriggered.can_transition(&locked()));
+        assert!(!AuthState::ResetInProgress.can_transition(&locked()));
+        assert!(!locked().can_transition(&AuthState::ResetInProgress));
+    }
+
+    #[test]
+    fn transition_is_checked_against_the_stored_state() {
+        let storage = MemPrngStore::new();
+        let identifier = Identifier::new("user@example.com");
+
+        block_on(async {
+            assert!(matches!(Account::create(&identifier, Role::User, &storage).await.unwrap(), SgStatusCode::StateChanged));
+            assert!(matches!(Account::create(&identifier, Role::User, &storage).await.unwrap(), SgStatusCode::AlreadyRegistered));
+
+            let denied = Account::transition(&identifier, AuthState::Transparent, &storage).await.unwrap();
+            assert!(matches!(denied, SgStatusCode::TransitionDenied));
+
+            assert!(matches!(Account::confirm_reset(&identifier, &storage).await.unwrap(), SgStatusCode::StateChanged));
+            let account = Account::load(&identifier, &storage).await.unwrap().unwrap();
+            assert_eq!(account.get_state(), &AuthState::ResetInProgress);
+        });
+    }
+
+    #[test]
+    fn credentials_are_only_set_while_a_reset_is_in_progress() {
+        let storage = MemPrngStore::new();
+        let identifier = Identifier::new("user@example.com");
+        let config = Argon2Config {
+            mem_cost: 64,
+            time_cost: 1,
+            lanes: 1,
+            hash_length: 16,
+        };
+        let first = Passphrase::new(SecretString::new("correct horse battery staple".into()));
+        let second = Passphrase::new(SecretString::new("tr0ub4dor&3".into()));
+
+        block_on(async {
+            assert!(matches!(Account::set_credentials(&identifier, &first, &storage, &config).await.unwrap(), SgStatusCode::Rejected));
+
+            Account::create(&identifier, Role::User, &storage).await.unwrap();
+            assert!(matches!(Account::set_credentials(&identifier, &first, &storage, &config).await.unwrap(), SgStatusCode::TransitionDenied));
+            assert!(storage.is_empty(PASSPHRASE_DOCUMENT).await);
+
+            Account::confirm_reset(&identifier, &storage).await.unwrap();
+            assert!(matches!(Account::set_credentials(&identifier, &first, &storage, &config).await.unwrap(), SgStatusCode::StateChanged));
+            assert_eq!(Account::load(&identifier, &storage).await.unwrap().unwrap().get_state(), &AuthState::Transparent);
+            assert!(matches!(first.verify(&identifier, &storage, &config).await.unwrap(), SgStatusCode::AuthenticPassphrase));
+
+            // A locked account can become `Transparent` but not through `set_credentials`
+            Account::transition(&identifier, locked(), &storage).await.unwrap();
+            assert!(matches!(Account::set_credentials(&identifier, &second, &storage, &config).await.unwrap(), SgStatusCode::TransitionDenied));
+            assert!(matches!(second.verify(&identifier, &storage, &config).await.unwrap(), SgStatusCode::Rejected));
+            assert!(matches!(first.verify(&identifier, &storage, &config).await.unwrap(), SgStatusCode::AuthenticPassphrase));
+        });
+    }
+}
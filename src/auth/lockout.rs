@@ -0,0 +1,477 @@
+use crate::{global::{
+    SgStatusCode,
+    Identifier,
+    Role,
+    LOCKOUT_DOCUMENT,
+}, storage::Storage, Account, AuthState, SgConfig, RoleHierarchy, PrngToken, ExpiryAction, LOCKOUT_MAX_SECS};
+
+use secrecy::{SecretString, ExposeSecret};
+use zeroize::Zeroize;
+use tai64::TAI64N;
+use anyhow::Result;
+use serde::{Serialize, Deserialize};
+use std::time::Duration;
+
+/// Length in bytes of a random unlock code
+pub const UNLOCK_CODE_LEN: usize = 16;
+
+/// ### How a temporary lock on an account is lifted
+/// Every variant except `Duration` is lifted by confirming a random unlock code
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TempLock {
+    /// Lifted once the TAI64N time has passed
+    Duration(TAI64N),
+    /// Lifted after the user confirms a random code sent to their email address
+    RandomToMail,
+    /// Lifted after the user confirms a random code from a logged in device
+    RandomToNode,
+    /// Lifted after another associated user confirms the random code from an authorized device
+    RandomToUser,
+    /// Lifted after the given number of associated users confirm the random code
+    RandomToMultiUser(usize),
+    /// Lifted after a `Role::SuperUser` confirms the random code
+    RandomToSuperUser,
+    /// Lifted after a `Role::Admin` confirms the random code
+    RandomToAdmin,
+    /// Lifted after a `Role::SubAdmin` confirms the random code
+    RandomToSubAdmin,
+    /// Lifted after the given number of associated nodes confirm the random code
+    RandomToMultiNode(usize),
+}
+
+impl TempLock {
+    /// The number of distinct confirmations needed to lift the lock
+    pub fn quorum(&self) -> usize {
+        match self {
+            TempLock::Duration(_) => 0,
+            TempLock::RandomToMultiUser(count) | TempLock::RandomToMultiNode(count) => (*count).max(1),
+            _ => 1,
+        }
+    }
+
+    /// Check whether `approver` may confirm the unlock code of `locked`, with role requirements checked against `hierarchy`
+    pub fn accepts(&self, locked: &Identifier, approver: &Approver, hierarchy: &RoleHierarchy) -> bool {
+        match self {
+            TempLock::Duration(_) => false,
+            TempLock::RandomToMail | TempLock::RandomToNode | TempLock::RandomToMultiNode(_) => true,
+            TempLock::RandomToUser | TempLock::RandomToMultiUser(_) => {
+                approver.identifier.storage_key() != locked.storage_key()
+            },
+            TempLock::RandomToSuperUser => hierarchy.satisfies(&approver.role, &Role::SuperUser),
+            TempLock::RandomToAdmin => hierarchy.satisfies(&approver.role, &Role::Admin),
+            TempLock::RandomToSubAdmin => hierarchy.satisfies(&approver.role, &Role::SubAdmin),
+        }
+    }
+}
+
+/// ### The user or node confirming an unlock code
+/// An approver can only be made from an authenticated session so the caller can not choose its identity or role
+pub struct Approver {
+    identifier: Identifier,
+    role: Role,
+}
+
+impl Approver {
+    fn new(identifier: Identifier, role: Role) -> Self {
+        Self { identifier, role }
+    }
+
+    /// The approver holding the session token stored under `hashed_token`, use `to_blake3()` on a `PrngToken`
+    /// or `CsprngToken::to_storage_key()` to get the hash. The identifier comes from the token and the role from
+    /// the stored `Account` of that identifier. Returns `None` unless the token is authentic and its account is `Transparent`
+    pub async fn from_session(hashed_token: &blake3::Hash, storage: &dyn Storage) -> Result<Option<Approver>> {
+        let token = match PrngToken::fetch(hashed_token, storage, ExpiryAction::default()).await? {
+            (SgStatusCode::AuthenticToken, Some(token)) => token,
+            _ => return Ok(None),
+        };
+
+        match Account::load(token.get_identifier(), storage).await? {
+            Some(account) if account.get_state() == &AuthState::Transparent => {
+                Ok(Some(Approver::new(account.get_identifier().clone(), account.get_role().clone())))
+            },
+            _ => Ok(None),
+        }
+    }
+}
+
+/// ### When to lock an identifier and how the lock is lifted
+pub struct LockoutPolicy {
+    threshold: u32,
+    lock_for: Duration,
+    unlock: TempLock,
+}
+
+impl Default for LockoutPolicy {
+    fn default() -> Self {
+        LockoutPolicy::from_config(&SgConfig::default())
+    }
+}
+
+impl LockoutPolicy {
+    /// A timed lock using the `[lockout]` section of the configuration
+    pub fn from_config(config: &SgConfig) -> Self {
+        Self {
+            threshold: config.lockout.threshold,
+            lock_for: Duration::from_secs(config.lockout.lock_secs.min(LOCKOUT_MAX_SECS)),
+            unlock: TempLock::Duration(TAI64N::now()),
+        }
+    }
+    /// Replace the number of failed attempts before locking
+    pub fn threshold(mut self, threshold: u32) -> Self {
+        self.threshold = threshold;
+
+        self
+    }
+    /// Replace how long a `TempLock::Duration` lasts
+    pub fn lock_for(mut self, duration: Duration) -> Self {
+        self.lock_for = duration;
+
+        self
+    }
+    /// Replace how the lock is lifted
+    pub fn unlock(mut self, unlock: TempLock) -> Self {
+        self.unlock = unlock;
+
+        self
+    }
+
+    fn new_lock(&self) -> TempLock {
+        match self.unlock {
+            TempLock::Duration(_) => TempLock::Duration(TAI64N::now() + self.lock_for),
+            ref strategy => strategy.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct LockoutRecord {
+    failures: u32,
+    lock: Option<TempLock>,
+    unlock_code: Option<[u8; blake3::OUT_LEN]>,
+    approvals: Vec<[u8; blake3::OUT_LEN]>,
+}
+
+/// ### Failed authentication counting and temporary locks per `Identifier`
+/// Records are kept under the `Identifier::storage_key()` in the `LOCKOUT_DOCUMENT` and
+/// only the `blake3` hash of an unlock code is stored.
+/// A `Transparent` account is moved to `AuthState::TempLock` while it is locked
+pub struct Lockout;
+
+impl Lockout {
+    /// Check whether an identifier may attempt to authenticate.
+    /// Returns `SgStatusCode::Locked` while a lock is in place, an expired `TempLock::Duration` is lifted here
+    pub async fn check(identifier: &Identifier, storage: &dyn Storage) -> Result<SgStatusCode> {
+        let key = identifier.storage_key();
+
+        let (data, record) = match Lockout::fetch(identifier, storage).await? {
+            Some(found) => found,
+            None => return Ok(SgStatusCode::AccessGranted),
+        };
+
+        match record.lock {
+            None => Ok(SgStatusCode::AccessGranted),
+            Some(TempLock::Duration(until)) if TAI64N::now() >= until => {
+                if storage.compare_and_swap(LOCKOUT_DOCUMENT, key.as_bytes(), Some(&data), None).await? {
+                    Lockout::release_account(identifier, storage).await?;
+                }
+
+                Ok(SgStatusCode::AccessGranted)
+            },
+            Some(_) => Ok(SgStatusCode::Locked),
+        }
+    }
+
+    /// Count a failed attempt, locking the identifier once the policy threshold is reached.
+    /// When a lock that needs an unlock code is placed the code is returned so that it can be
+    /// delivered to whoever has to confirm it, it is not stored and can not be recovered later
+    pub async fn record_failure(identifier: &Identifier, storage: &dyn Storage, policy: &LockoutPolicy) -> Result<(SgStatusCode, Option<SecretString>)> {
+        let key = identifier.storage_key();
+
+        loop {
+            let (current, mut record) = match Lockout::fetch(identifier, storage).await? {
+                Some((data, record)) => (Some(data), record),
+                None => (None, LockoutRecord::default()),
+            };
+
+            if record.lock.is_some() {
+                return Ok((SgStatusCode::Locked, None));
+            }
+
+            record.failures = record.failures.saturating_add(1);
+
+            let mut code = None;
+            if record.failures >= policy.threshold {
+                let lock = policy.new_lock();
+
+                if lock.quorum() > 0 {
+                    let mut raw = [0_u8; UNLOCK_CODE_LEN];
+                    getrandom::getrandom(&mut raw).map_err(|error| anyhow::anyhow!("{}", error))?;
+
+                    let encoded = hex::encode(&raw);
+                    record.unlock_code = Some(*blake3::hash(encoded.as_bytes()).as_bytes());
+                    code = Some(SecretString::new(encoded));
+                    raw.zeroize();
+                }
+
+                record.lock = Some(lock);
+                record.approvals.clear();
+            }
+
+            let updated = bincode::serialize::<LockoutRecord>(&record)?;
+            if !storage.compare_and_swap(LOCKOUT_DOCUMENT, key.as_bytes(), current.as_deref(), Some(&updated)).await? {
+                continue;
+            }
+
+            return match record.lock {
+                Some(lock) => {
+                    Account::transition(identifier, AuthState::TempLock(lock), storage).await?;
+
+                    Ok((SgStatusCode::Locked, code))
+                },
+                None => Ok((SgStatusCode::Rejected, None)),
+            };
+        }
+    }
+
+    /// Clear the failed attempts after a successful authentication of an identifier that is not locked
+    pub async fn record_success(identifier: &Identifier, storage: &dyn Storage) -> Result<SgStatusCode> {
+        let key = identifier.storage_key();
+
+        match Lockout::fetch(identifier, storage).await? {
+            Some((_, LockoutRecord { lock: Some(_), .. })) => Ok(SgStatusCode::Locked),
+            Some((data, _)) => {
+                storage.compare_and_swap(LOCKOUT_DOCUMENT, key.as_bytes(), Some(&data), None).await?;
+
+                Ok(SgStatusCode::AccessGranted)
+            },
+            None => Ok(SgStatusCode::AccessGranted),
+        }
+    }
+
+    /// Confirm the unlock code of a locked identifier on behalf of `approver`.
+    /// Returns `SgStatusCode::UnlockPending` with the number of confirmations still needed,
+    /// `SgStatusCode::Unlocked` once the quorum is reached, `SgStatusCode::AccessDenied` if the code is wrong,
+    /// the approver already confirmed it or is not allowed to confirm it by the `TempLock` and `hierarchy`,
+    /// and `SgStatusCode::Rejected` if the identifier is not locked
+    pub async fn confirm_unlock(identifier: &Identifier, code: &SecretString, approver: &Approver, hierarchy: &RoleHierarchy, storage: &dyn Storage) -> Result<SgStatusCode> {
+        let key = identifier.storage_key();
+        let presented = blake3::hash(code.expose_secret().as_bytes());
+
+        loop {
+            let (data, mut record) = match Lockout::fetch(identifier, storage).await? {
+                Some(found) => found,
+                None => return Ok(SgStatusCode::Rejected),
+            };
+
+            let lock = match record.lock.clone() {
+                Some(lock) => lock,
+                None => return Ok(SgStatusCode::Rejected),
+            };
+
+            // `blake3::Hash` compares in constant time
+            let code_matches = match record.unlock_code {
+                Some(expected) => blake3::Hash::from(expected) == presented,
+                None => false,
+            };
+
+            if !code_matches || !lock.accepts(identifier, approver, hierarchy) {
+                return Ok(SgStatusCode::AccessDenied);
+            }
+
+            let approver_key = *approver.identifier.storage_key().as_bytes();
+            if record.approvals.contains(&approver_key) {
+                return Ok(SgStatusCode::AccessDenied);
+            }
+            record.approvals.push(approver_key);
+
+            let remaining = lock.quorum().saturating_sub(record.approvals.len());
+            let updated = match remaining {
+                0 => None,
+                _ => Some(bincode::serialize::<LockoutRecord>(&record)?),
+            };
+
+            if !storage.compare_and_swap(LOCKOUT_DOCUMENT, key.as_bytes(), Some(&data), updated.as_deref()).await? {
+                continue;
+            }
+
+            if remaining > 0 {
+                return Ok(SgStatusCode::UnlockPending(remaining));
+            }
+
+            Lockout::release_account(identifier, storage).await?;
+
+            return Ok(SgStatusCode::Unlocked);
+        }
+    }
+
+    async fn release_account(identifier: &Identifier, storage: &dyn Storage) -> Result<()> {
+        if let Some(account) = Account::load(identifier, storage).await? {
+            if let AuthState::TempLock(_) = account.get_state() {
+                Account::transition(identifier, AuthState::Transparent, storage).await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn fetch(identifier: &Identifier, storage: &dyn Storage) -> Result<Option<(Vec<u8>, LockoutRecord)>> {
+        match storage.get(LOCKOUT_DOCUMENT, identifier.storage_key().as_bytes()).await? {
+            Some(data) => {
+                let record = bincode::deserialize::<LockoutRecord>(&data)?;
+
+                Ok(Some((data, record)))
+            },
+            None => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{global::Lease, MemPrngStore};
+    use futures_lite::future::block_on;
+
+    /// A `Transparent` account with a stored session, returned as its hashed token
+    async fn session(name: &str, role: Role, storage: &dyn Storage) -> blake3::Hash {
+        let identifier = Identifier::new(name);
+        Account::create(&identifier, role.clone(), storage).await.unwrap();
+        Account::transition(&identifier, AuthState::ResetInProgress, storage).await.unwrap();
+        Account::transition(&identifier, AuthState::Transparent, storage).await.unwrap();
+
+        let token = PrngToken::default().identifier(identifier).role(role).lease(Lease::Lifetime);
+        let hashed_token = token.token_hash();
+        token.store(&hashed_token, storage).await.unwrap();
+
+        hashed_token
+    }
+
+    async fn approver(name: &str, role: Role, storage: &dyn Storage) -> Approver {
+        let hashed_token = session(name, role, storage).await;
+
+        Approver::from_session(&hashed_token, storage).await.unwrap().unwrap()
+    }
+
+    async fn lock(identifier: &Identifier, unlock: TempLock, storage: &dyn Storage) -> SecretString {
+        let policy = LockoutPolicy::default().threshold(1).unlock(unlock);
+
+        match Lockout::record_failure(identifier, storage, &policy).await.unwrap() {
+            (SgStatusCode::Locked, Some(code)) => code,
+            (status, _) => panic!("{:?}", status),
+        }
+    }
+
+    #[test]
+    fn approvers_come_from_authenticated_sessions() {
+        let storage = MemPrngStore::new();
+
+        block_on(async {
+            assert!(Approver::from_session(&blake3::hash(b"unknown"), &storage).await.unwrap().is_none());
+
+            // A session whose identifier has no account
+            let token = PrngToken::default().identifier(Identifier::new("ghost@example.com")).role(Role::SuperUser).lease(Lease::Lifetime);
+            let hashed_token = token.token_hash();
+            token.store(&hashed_token, &storage).await.unwrap();
+            assert!(Approver::from_session(&hashed_token, &storage).await.unwrap().is_none());
+
+            // The role of the account is used, not the role of the session
+            let identifier = Identifier::new("user@example.com");
+            Account::create(&identifier, Role::User, &storage).await.unwrap();
+            Account::transition(&identifier, AuthState::ResetInProgress, &storage).await.unwrap();
+            Account::transition(&identifier, AuthState::Transparent, &storage).await.unwrap();
+            let token = PrngToken::default().identifier(identifier).role(Role::SuperUser).lease(Lease::Lifetime);
+            let hashed_token = token.token_hash();
+            token.store(&hashed_token, &storage).await.unwrap();
+
+            let approver = Approver::from_session(&hashed_token, &storage).await.unwrap().unwrap();
+            assert_eq!(approver.role, Role::User);
+        });
+    }
+
+    #[test]
+    fn quorum_needs_distinct_approvers() {
+        let storage = MemPrngStore::new();
+        let hierarchy = RoleHierarchy::default();
+        let locked = Identifier::new("locked@example.com");
+
+        block_on(async {
+            let code = lock(&locked, TempLock::RandomToMultiUser(2), &storage).await;
+            let first = approver("first@example.com", Role::User, &storage).await;
+            let second = approver("second@example.com", Role::User, &storage).await;
+
+            let status = Lockout::confirm_unlock(&locked, &code, &first, &hierarchy, &storage).await.unwrap();
+            assert!(matches!(status, SgStatusCode::UnlockPending(1)));
+
+            let status = Lockout::confirm_unlock(&locked, &code, &first, &hierarchy, &storage).await.unwrap();
+            assert!(matches!(status, SgStatusCode::AccessDenied));
+            assert!(matches!(Lockout::check(&locked, &storage).await.unwrap(), SgStatusCode::Locked));
+
+            let status = Lockout::confirm_unlock(&locked, &code, &second, &hierarchy, &storage).await.unwrap();
+            assert!(matches!(status, SgStatusCode::Unlocked));
+            assert!(matches!(Lockout::check(&locked, &storage).await.unwrap(), SgStatusCode::AccessGranted));
+        });
+    }
+
+    #[test]
+    fn approvers_need_the_required_role() {
+        let storage = MemPrngStore::new();
+        let hierarchy = RoleHierarchy::default();
+        let locked = Identifier::new("locked@example.com");
+
+        block_on(async {
+            let code = lock(&locked, TempLock::RandomToAdmin, &storage).await;
+            let user = approver("user@example.com", Role::User, &storage).await;
+            let admin = approver("admin@example.com", Role::Admin, &storage).await;
+
+            let status = Lockout::confirm_unlock(&locked, &code, &user, &hierarchy, &storage).await.unwrap();
+            assert!(matches!(status, SgStatusCode::AccessDenied));
+
+            let status = Lockout::confirm_unlock(&locked, &code, &admin, &hierarchy, &storage).await.unwrap();
+            assert!(matches!(status, SgStatusCode::Unlocked));
+        });
+    }
+
+    #[test]
+    fn codes_can_not_be_reused_or_outlive_their_lock() {
+        let storage = MemPrngStore::new();
+        let hierarchy = RoleHierarchy::default();
+        let locked = Identifier::new("locked@example.com");
+
+        block_on(async {
+            let approver = approver("locked-device@example.com", Role::User, &storage).await;
+
+            let first = lock(&locked, TempLock::RandomToMail, &storage).await;
+            let status = Lockout::confirm_unlock(&locked, &first, &approver, &hierarchy, &storage).await.unwrap();
+            assert!(matches!(status, SgStatusCode::Unlocked));
+
+            // Used, the lock it belonged to is gone
+            let status = Lockout::confirm_unlock(&locked, &first, &approver, &hierarchy, &storage).await.unwrap();
+            assert!(matches!(status, SgStatusCode::Rejected));
+
+            // Expired, a new lock has a new code
+            let second = lock(&locked, TempLock::RandomToMail, &storage).await;
+            let status = Lockout::confirm_unlock(&locked, &first, &approver, &hierarchy, &storage).await.unwrap();
+            assert!(matches!(status, SgStatusCode::AccessDenied));
+
+            let status = Lockout::confirm_unlock(&locked, &second, &approver, &hierarchy, &storage).await.unwrap();
+            assert!(matches!(status, SgStatusCode::Unlocked));
+        });
+    }
+
+    #[test]
+    fn approver_roles_are_checked_against_the_configured_hierarchy() {
+        let security = Role::Specifed("Security".into());
+        let mut hierarchy = RoleHierarchy::default();
+        hierarchy.insert(&security, Some(&Role::Admin), Some(&Role::SuperUser)).unwrap();
+
+        let locked = Identifier::new("user@example.com");
+        let approver = Approver::new(Identifier::new("security@example.com"), security);
+
+        assert!(TempLock::RandomToAdmin.accepts(&locked, &approver, &hierarchy));
+        assert!(!TempLock::RandomToAdmin.accepts(&locked, &approver, &RoleHierarchy::default()));
+        assert!(!TempLock::RandomToSuperUser.accepts(&locked, &approver, &hierarchy));
+
+        let impostor = Approver::new(Identifier::new("impostor@example.com"), Role::Specifed("SuperUser".into()));
+        assert!(!TempLock::RandomToSuperUser.accepts(&locked, &impostor, &hierarchy));
+    }
+}
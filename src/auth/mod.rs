@@ -0,0 +1,11 @@
+mod passphrase;
+mod account;
+mod lockout;
+mod otp;
+mod recovery;
+
+pub use passphrase::*;
+pub use account::*;
+pub use lockout::*;
+pub use otp::*;
+pub use recovery::*;
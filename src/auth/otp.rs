@@ -0,0 +1,504 @@
+use crate::{global::{
+    SgStatusCode,
+    Identifier,
+    OTP_DOCUMENT,
+}, storage::Storage, Keyring, KeyPurpose, SgConfig, QrImage, Lockout, LockoutPolicy, LoginSecret, OTP_MAX_WINDOW};
+
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce, aead::{Aead, NewAead, Payload}};
+use hmac::{Hmac, Mac, NewMac};
+use sha1::Sha1;
+use sha2::{Sha256, Sha512};
+use data_encoding::BASE32_NOPAD;
+use secrecy::{SecretString, SecretVec, ExposeSecret};
+use zeroize::Zeroize;
+use anyhow::{Result, anyhow, bail};
+use serde::{Serialize, Deserialize};
+use std::{convert::TryInto, time::{Duration, SystemTime, UNIX_EPOCH}};
+
+/// Length in bytes of the random ChaCha20-Poly1305 nonce an enrolled secret is sealed with
+pub const OTP_NONCE_LEN: usize = 12;
+/// Length in bytes of a `KeyPurpose::OtpSecret` key
+pub const OTP_KEY_LEN: usize = 32;
+
+/// The HMAC algorithm of a one-time password
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OtpAlgorithm {
+    Sha1,
+    Sha256,
+    Sha512,
+}
+
+impl OtpAlgorithm {
+    /// The name used in `otpauth://` URIs
+    pub fn uri_name(&self) -> &'static str {
+        match self {
+            OtpAlgorithm::Sha1 => "SHA1",
+            OtpAlgorithm::Sha256 => "SHA256",
+            OtpAlgorithm::Sha512 => "SHA512",
+        }
+    }
+
+    /// Length in bytes of a secret for this algorithm, the output size of the hash as RFC 6238 uses
+    pub fn secret_len(&self) -> usize {
+        match self {
+            OtpAlgorithm::Sha1 => 20,
+            OtpAlgorithm::Sha256 => 32,
+            OtpAlgorithm::Sha512 => 64,
+        }
+    }
+}
+
+/// Whether codes are derived from a counter or from the time
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum OtpKind {
+    /// RFC 4226, the counter moves forward with every accepted code
+    Hotp,
+    /// RFC 6238, the counter is the number of periods since the Unix epoch
+    Totp,
+}
+
+/// ### How one-time passwords are made and checked
+pub struct OtpPolicy {
+    issuer: String,
+    algorithm: OtpAlgorithm,
+    digits: u32,
+    period: Duration,
+    window: u64,
+}
+
+impl Default for OtpPolicy {
+    fn default() -> Self {
+        OtpPolicy::from_config(&SgConfig::default())
+    }
+}
+
+impl OtpPolicy {
+    /// A policy using the `[otp]` section of the configuration
+    pub fn from_config(config: &SgConfig) -> Self {
+        Self {
+            issuer: config.otp.issuer.clone(),
+            algorithm: config.otp.algorithm,
+            digits: config.otp.digits,
+            period: Duration::from_secs(config.otp.period_secs),
+            window: config.otp.window.min(OTP_MAX_WINDOW),
+        }
+    }
+    /// Replace the issuer shown by authenticator apps
+    pub fn issuer(mut self, issuer: &str) -> Self {
+        self.issuer = issuer.into();
+
+        self
+    }
+    /// Replace the HMAC algorithm of new enrollments
+    pub fn algorithm(mut self, algorithm: OtpAlgorithm) -> Self {
+        self.algorithm = algorithm;
+
+        self
+    }
+    /// Replace the number of digits of new enrollments
+    pub fn digits(mut self, digits: u32) -> Self {
+        self.digits = digits;
+
+        self
+    }
+    /// Replace the TOTP period of new enrollments
+    pub fn period(mut self, period: Duration) -> Self {
+        self.period = period;
+
+        self
+    }
+    /// Replace how many codes either side of the expected one are accepted, at most `OTP_MAX_WINDOW`
+    pub fn window(mut self, window: u64) -> Self {
+        self.window = window.min(OTP_MAX_WINDOW);
+
+        self
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct OtpRecord {
+    kind: OtpKind,
+    algorithm: OtpAlgorithm,
+    digits: u32,
+    period: u64,
+    kid: String,
+    sealed: Vec<u8>,
+    last_counter: Option<u64>,
+}
+
+/// The secret of a new enrollment, to be shown to the user once
+pub struct OtpEnrollment {
+    secret: SecretString,
+    uri: SecretString,
+    qr: QrImage,
+}
+
+impl OtpEnrollment {
+    /// The base32 secret for typing into an authenticator app
+    pub fn get_secret(&self) -> &SecretString {
+        &self.secret
+    }
+
+    /// The `otpauth://` URI for an authenticator app
+    pub fn get_uri(&self) -> &SecretString {
+        &self.uri
+    }
+
+    /// The `otpauth://` URI as a QR code for an authenticator app to scan
+    pub fn get_qr(&self) -> &QrImage {
+        &self.qr
+    }
+}
+
+/// ### HOTP and TOTP one-time passwords per `Identifier`
+/// Records are kept under the `Identifier::storage_key()` in the `OTP_DOCUMENT`. The secret is sealed with
+/// ChaCha20-Poly1305 using the active `KeyPurpose::OtpSecret` key, with the storage key as associated data
+/// so a sealed secret can not be moved to another identifier. The algorithm, digits and period are kept
+/// with the record so later policy changes do not break enrolled authenticators.
+/// The last accepted counter is remembered and a code for it or an earlier counter is never accepted again.
+/// Wrong codes are counted by the `Lockout` of the identifier, the same one passphrase logins use
+pub struct Otp;
+
+impl Otp {
+    /// Enroll an identifier, the identifier is the account name shown by authenticator apps.
+    /// Returns `SgStatusCode::Enrolled` with the secret and URI to hand to the user,
+    /// or `SgStatusCode::AlreadyRegistered` without an enrollment if the identifier is already enrolled
+    pub async fn enroll(identifier: &Identifier, kind: OtpKind, policy: &OtpPolicy, keyring: &Keyring, storage: &dyn Storage) -> Result<(SgStatusCode, Option<OtpEnrollment>)> {
+        if !(6..=8).contains(&policy.digits) {
+            bail!("A one-time password must have between 6 and 8 digits");
+        }
+        if policy.period.as_secs() == 0 {
+            bail!("The TOTP period must be at least one second");
+        }
+
+        let key = identifier.storage_key();
+
+        let mut secret = vec![0_u8; policy.algorithm.secret_len()];
+        getrandom::getrandom(&mut secret).map_err(|error| anyhow!("{}", error))?;
+        let secret = SecretVec::new(secret);
+
+        let mut nonce = [0_u8; OTP_NONCE_LEN];
+        getrandom::getrandom(&mut nonce).map_err(|error| anyhow!("{}", error))?;
+
+        let sealed = keyring.with_active(KeyPurpose::OtpSecret, |kid, sealing_key| {
+            if sealing_key.len() != OTP_KEY_LEN {
+                bail!("The `{}` key must be {} bytes", kid, OTP_KEY_LEN);
+            }
+
+            let ciphertext = ChaCha20Poly1305::new(Key::from_slice(sealing_key))
+                .encrypt(Nonce::from_slice(&nonce), Payload { msg: secret.expose_secret(), aad: key.as_bytes() })
+                .map_err(|_| anyhow!("Unable to seal the secret"))?;
+
+            let mut sealed = nonce.to_vec();
+            sealed.extend_from_slice(&ciphertext);
+
+            Ok((kid.to_owned(), sealed))
+        }).await;
+
+        let (kid, sealed) = match sealed {
+            Some(sealed) => sealed?,
+            None => bail!("There is no active {:?} key in the keyring", KeyPurpose::OtpSecret),
+        };
+
+        let record = OtpRecord {
+            kind,
+            algorithm: policy.algorithm,
+            digits: policy.digits,
+            period: policy.period.as_secs(),
+            kid,
+            sealed,
+            last_counter: None,
+        };
+        let data = bincode::serialize::<OtpRecord>(&record)?;
+
+        let encoded = BASE32_NOPAD.encode(secret.expose_secret());
+        let uri = SecretString::new(Otp::uri(&identifier.0, &encoded, &record, &policy.issuer));
+        let qr = QrImage::from_secret(&uri)?;
+
+        if !storage.compare_and_swap(OTP_DOCUMENT, key.as_bytes(), None, Some(&data)).await? {
+            return Ok((SgStatusCode::AlreadyRegistered, None));
+        }
+
+        Ok((SgStatusCode::Enrolled, Some(OtpEnrollment {
+            secret: SecretString::new(encoded),
+            uri,
+            qr,
+        })))
+    }
+
+    /// Check a code for an identifier, counting a wrong code as a failed attempt against `lockout`.
+    /// Returns `SgStatusCode::AccessGranted` if it matches a counter within the window that is after the last
+    /// accepted one, `SgStatusCode::AccessDenied` if it does not, `SgStatusCode::Locked` while the identifier
+    /// is locked and `SgStatusCode::Rejected` if the identifier is not enrolled or its secret can not be unsealed.
+    /// If a wrong code places a lock that needs an unlock code, the code is returned as `LoginSecret::UnlockCode`
+    pub async fn verify(identifier: &Identifier, code: &str, policy: &OtpPolicy, lockout: &LockoutPolicy, keyring: &Keyring, storage: &dyn Storage) -> Result<(SgStatusCode, Option<LoginSecret>)> {
+        if let SgStatusCode::Locked = Lockout::check(identifier, storage).await? {
+            return Ok((SgStatusCode::Locked, None));
+        }
+
+        match Otp::check(identifier, code, policy, keyring, storage).await? {
+            SgStatusCode::AccessGranted => {
+                Lockout::record_success(identifier, storage).await?;
+
+                Ok((SgStatusCode::AccessGranted, None))
+            },
+            SgStatusCode::AccessDenied => match Lockout::record_failure(identifier, storage, lockout).await? {
+                (SgStatusCode::Locked, code) => Ok((SgStatusCode::Locked, code.map(LoginSecret::UnlockCode))),
+                _ => Ok((SgStatusCode::AccessDenied, None)),
+            },
+            status => Ok((status, None)),
+        }
+    }
+
+    async fn check(identifier: &Identifier, code: &str, policy: &OtpPolicy, keyring: &Keyring, storage: &dyn Storage) -> Result<SgStatusCode> {
+        let key = identifier.storage_key();
+
+        loop {
+            let (data, mut record) = match Otp::fetch(identifier, storage).await? {
+                Some(found) => found,
+                None => return Ok(SgStatusCode::Rejected),
+            };
+
+            let secret = match Otp::unseal(&record, &key, keyring).await {
+                Some(secret) => secret,
+                None => return Ok(SgStatusCode::Rejected),
+            };
+
+            let (first, last) = match record.kind {
+                OtpKind::Hotp => {
+                    let next = record.last_counter.map_or(0, |counter| counter.saturating_add(1));
+
+                    (next, next.saturating_add(policy.window))
+                },
+                OtpKind::Totp => {
+                    let step = Otp::time_step(SystemTime::now(), record.period)?;
+
+                    (step.saturating_sub(policy.window), step.saturating_add(policy.window))
+                },
+            };
+
+            let mut matched = None;
+            for counter in first..=last {
+                if record.last_counter.map_or(false, |accepted| counter <= accepted) {
+                    continue;
+                }
+
+                let expected = Otp::hotp(secret.expose_secret(), counter, record.algorithm, record.digits)?;
+                if constant_time_eq::constant_time_eq(expected.as_bytes(), code.as_bytes()) {
+                    matched = Some(counter);
+                    break;
+                }
+            }
+
+            let counter = match matched {
+                Some(counter) => counter,
+                None => return Ok(SgStatusCode::AccessDenied),
+            };
+
+            record.last_counter = Some(counter);
+            let updated = bincode::serialize::<OtpRecord>(&record)?;
+
+            if storage.compare_and_swap(OTP_DOCUMENT, key.as_bytes(), Some(&data), Some(&updated)).await? {
+                return Ok(SgStatusCode::AccessGranted);
+            }
+        }
+    }
+
+    /// Remove the enrollment of an identifier.
+    /// Returns `true` if there was one
+    pub async fn remove(identifier: &Identifier, storage: &dyn Storage) -> Result<bool> {
+        storage.delete(OTP_DOCUMENT, identifier.storage_key().as_bytes()).await
+    }
+
+    /// The RFC 4226 code for `counter`
+    pub fn hotp(secret: &[u8], counter: u64, algorithm: OtpAlgorithm, digits: u32) -> Result<String> {
+        if !(1..=9).contains(&digits) {
+            bail!("A one-time password must have between 1 and 9 digits");
+        }
+
+        let message = counter.to_be_bytes();
+        let mut digest = match algorithm {
+            OtpAlgorithm::Sha1 => {
+                let mut mac = Hmac::<Sha1>::new_varkey(secret).map_err(|error| anyhow!("{}", error))?;
+                mac.update(&message);
+                mac.finalize().into_bytes().to_vec()
+            },
+            OtpAlgorithm::Sha256 => {
+                let mut mac = Hmac::<Sha256>::new_varkey(secret).map_err(|error| anyhow!("{}", error))?;
+                mac.update(&message);
+                mac.finalize().into_bytes().to_vec()
+            },
+            OtpAlgorithm::Sha512 => {
+                let mut mac = Hmac::<Sha512>::new_varkey(secret).map_err(|error| anyhow!("{}", error))?;
+                mac.update(&message);
+                mac.finalize().into_bytes().to_vec()
+            },
+        };
+
+        // Dynamic truncation, RFC 4226 section 5.3
+        let offset = (digest[digest.len() - 1] & 0x0f) as usize;
+        let truncated: [u8; 4] = digest[offset..offset + 4].try_into()?;
+        let binary = u32::from_be_bytes(truncated) & 0x7fff_ffff;
+        digest.zeroize();
+
+        Ok(format!("{:0width$}", binary % 10_u32.pow(digits), width = digits as usize))
+    }
+
+    /// The RFC 6238 code at `time`
+    pub fn totp(secret: &[u8], time: SystemTime, period: Duration, algorithm: OtpAlgorithm, digits: u32) -> Result<String> {
+        Otp::hotp(secret, Otp::time_step(time, period.as_secs())?, algorithm, digits)
+    }
+
+    fn time_step(time: SystemTime, period: u64) -> Result<u64> {
+        if period == 0 {
+            bail!("The TOTP period must be at least one second");
+        }
+
+        Ok(time.duration_since(UNIX_EPOCH)?.as_secs() / period)
+    }
+
+    async fn unseal(record: &OtpRecord, key: &blake3::Hash, keyring: &Keyring) -> Option<SecretVec<u8>> {
+        if record.sealed.len() <= OTP_NONCE_LEN {
+            return None;
+        }
+        let (nonce, ciphertext) = record.sealed.split_at(OTP_NONCE_LEN);
+
+        keyring.with_key(KeyPurpose::OtpSecret, &record.kid, |sealing_key| {
+            if sealing_key.len() != OTP_KEY_LEN {
+                return None;
+            }
+
+            ChaCha20Poly1305::new(Key::from_slice(sealing_key))
+                .decrypt(Nonce::from_slice(nonce), Payload { msg: ciphertext, aad: key.as_bytes() })
+                .ok()
+                .map(SecretVec::new)
+        }).await.flatten()
+    }
+
+    /// `otpauth://<kind>/<issuer>:<account>?secret=..&issuer=..&algorithm=..&digits=..` with the `period` or `counter`
+    fn uri(account: &str, secret: &str, record: &OtpRecord, issuer: &str) -> String {
+        let (kind, parameter) = match record.kind {
+            OtpKind::Hotp => ("hotp", format!("counter={}", record.last_counter.map_or(0, |counter| counter + 1))),
+            OtpKind::Totp => ("totp", format!("period={}", record.period)),
+        };
+
+        format!(
+            "otpauth://{}/{}:{}?secret={}&issuer={}&algorithm={}&digits={}&{}",
+            kind,
+            Otp::percent_encode(issuer),
+            Otp::percent_encode(account),
+            secret,
+            Otp::percent_encode(issuer),
+            record.algorithm.uri_name(),
+            record.digits,
+            parameter,
+        )
+    }
+
+    fn percent_encode(value: &str) -> String {
+        value.bytes()
+            .map(|byte| match byte {
+                b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => (byte as char).to_string(),
+                _ => format!("%{:02X}", byte),
+            })
+            .collect()
+    }
+
+    async fn fetch(identifier: &Identifier, storage: &dyn Storage) -> Result<Option<(Vec<u8>, OtpRecord)>> {
+        match storage.get(OTP_DOCUMENT, identifier.storage_key().as_bytes()).await? {
+            Some(data) => {
+                let record = bincode::deserialize::<OtpRecord>(&data)?;
+
+                Ok(Some((data, record)))
+            },
+            None => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{MemPrngStore, TempLock};
+    use futures_lite::future::block_on;
+
+    const TOTP_TIMES: [u64; 6] = [59, 1_111_111_109, 1_111_111_111, 1_234_567_890, 2_000_000_000, 20_000_000_000];
+
+    fn totp_at(secret: &[u8], seconds: u64, algorithm: OtpAlgorithm) -> String {
+        Otp::totp(secret, UNIX_EPOCH + Duration::from_secs(seconds), Duration::from_secs(30), algorithm, 8).unwrap()
+    }
+
+    #[test]
+    fn rfc4226_appendix_d() {
+        let expected = ["755224", "287082", "359152", "969429", "338314", "254676", "287922", "162583", "399871", "520489"];
+
+        for (counter, code) in expected.iter().enumerate() {
+            assert_eq!(Otp::hotp(b"12345678901234567890", counter as u64, OtpAlgorithm::Sha1, 6).unwrap(), *code);
+        }
+    }
+
+    #[test]
+    fn rfc6238_appendix_b() {
+        let vectors: [(&[u8], OtpAlgorithm, [&str; 6]); 3] = [
+            (b"12345678901234567890", OtpAlgorithm::Sha1,
+                ["94287082", "07081804", "14050471", "89005924", "69279037", "65353130"]),
+            (b"12345678901234567890123456789012", OtpAlgorithm::Sha256,
+                ["46119246", "68084774", "67062674", "91819424", "90698825", "77737706"]),
+            (b"1234567890123456789012345678901234567890123456789012345678901234", OtpAlgorithm::Sha512,
+                ["90693936", "25091201", "99943326", "93441116", "38618901", "47863826"]),
+        ];
+
+        for (secret, algorithm, expected) in vectors.iter() {
+            for (seconds, code) in TOTP_TIMES.iter().zip(expected.iter()) {
+                assert_eq!(totp_at(secret, *seconds, *algorithm), *code);
+            }
+        }
+    }
+
+    #[test]
+    fn window_is_capped() {
+        assert_eq!(OtpPolicy::default().window(u64::MAX).window, OTP_MAX_WINDOW);
+
+        let mut config = SgConfig::default();
+        config.otp.window = OTP_MAX_WINDOW + 1;
+        assert!(config.validate().is_err());
+        assert_eq!(OtpPolicy::from_config(&config).window, OTP_MAX_WINDOW);
+    }
+
+    #[test]
+    fn codes_are_single_use_and_failures_lock() {
+        let storage = MemPrngStore::new();
+        let keyring = Keyring::new();
+        let identifier = Identifier::new("user@example.com");
+        let policy = OtpPolicy::default();
+        let lockout = LockoutPolicy::default().threshold(2).unlock(TempLock::RandomToMail);
+
+        block_on(async {
+            keyring.install(KeyPurpose::OtpSecret, "otp-1", SecretVec::new(vec![9_u8; OTP_KEY_LEN])).await.unwrap();
+            keyring.activate(KeyPurpose::OtpSecret, "otp-1").await.unwrap();
+
+            let enrollment = match Otp::enroll(&identifier, OtpKind::Hotp, &policy, &keyring, &storage).await.unwrap() {
+                (SgStatusCode::Enrolled, Some(enrollment)) => enrollment,
+                (status, _) => panic!("{:?}", status),
+            };
+            let secret = BASE32_NOPAD.decode(enrollment.get_secret().expose_secret().as_bytes()).unwrap();
+            let first = Otp::hotp(&secret, 0, OtpAlgorithm::Sha1, 6).unwrap();
+
+            let (status, _) = Otp::verify(&identifier, &first, &policy, &lockout, &keyring, &storage).await.unwrap();
+            assert!(matches!(status, SgStatusCode::AccessGranted));
+
+            let (status, unlock) = Otp::verify(&identifier, &first, &policy, &lockout, &keyring, &storage).await.unwrap();
+            assert!(matches!(status, SgStatusCode::AccessDenied));
+            assert!(unlock.is_none());
+
+            let (status, unlock) = Otp::verify(&identifier, &first, &policy, &lockout, &keyring, &storage).await.unwrap();
+            assert!(matches!(status, SgStatusCode::Locked));
+            assert!(matches!(unlock, Some(LoginSecret::UnlockCode(_))));
+
+            let next = Otp::hotp(&secret, 1, OtpAlgorithm::Sha1, 6).unwrap();
+            let (status, _) = Otp::verify(&identifier, &next, &policy, &lockout, &keyring, &storage).await.unwrap();
+            assert!(matches!(status, SgStatusCode::Locked));
+        });
+    }
+}
@@ -0,0 +1,202 @@
+use crate::{global::{
+    SgStatusCode,
+    Identifier,
+    SecurityCheck,
+    PASSPHRASE_DOCUMENT,
+}, storage::Storage, Argon2Config, PrngToken, CsprngToken, Lockout, LockoutPolicy};
+
+use secrecy::{SecretString, ExposeSecret};
+use anyhow::Result;
+
+/// Length in bytes of the random salt of each hash
+pub const PASSPHRASE_SALT_LEN: usize = 16;
+
+/// ### A secret that comes out of `Passphrase::login()`
+/// A session token and an unlock code never share a slot, so an unlock code can not be
+/// handed to the client that failed to log in as if it were its session token
+#[derive(Debug)]
+pub enum LoginSecret {
+    /// The token issued to the authenticated identifier
+    Session(SecretString),
+    /// The code of a lock placed by this attempt. It has to be delivered out of band
+    /// to whoever confirms it, see `Lockout::confirm_unlock()`, and never to the client
+    UnlockCode(SecretString),
+}
+
+/// ### A passphrase supplied by a user
+/// Only the argon2id hash of a passphrase in PHC string format is ever stored,
+/// under the `Identifier::storage_key()` of its owner in the `PASSPHRASE_DOCUMENT`.
+/// The passphrase is held in a `SecretString` so it is zeroized when dropped and
+/// hashes are compared in constant time by `argon2::verify_encoded`
+pub struct Passphrase(SecretString);
+
+impl Passphrase {
+    pub fn new(passphrase: SecretString) -> Self {
+        Self(passphrase)
+    }
+
+    /// Store the hash of the passphrase for an identifier that does not have one yet
+    pub async fn register(&self, identifier: &Identifier, storage: &dyn Storage, config: &Argon2Config) -> Result<SgStatusCode> {
+        let phc = self.hash(config)?;
+
+        match storage.compare_and_swap(PASSPHRASE_DOCUMENT, identifier.storage_key().as_bytes(), None, Some(phc.as_bytes())).await? {
+            true => Ok(SgStatusCode::AuthenticPassphrase),
+            false => Ok(SgStatusCode::AlreadyRegistered),
+        }
+    }
+
+    /// Replace the passphrase of an identifier, the caller must have verified the old one
+    pub async fn change(&self, identifier: &Identifier, storage: &dyn Storage, config: &Argon2Config) -> Result<SgStatusCode> {
+        let phc = self.hash(config)?;
+        storage.put(PASSPHRASE_DOCUMENT, identifier.storage_key().as_bytes(), phc.as_bytes()).await?;
+
+        Ok(SgStatusCode::AuthenticPassphrase)
+    }
+
+    /// Check the passphrase against the stored hash.
+    /// An unknown identifier still costs one hash so it can not be told apart from a wrong passphrase by timing
+    pub async fn verify(&self, identifier: &Identifier, storage: &dyn Storage, config: &Argon2Config) -> Result<SgStatusCode> {
+        let phc = match Passphrase::stored_hash(identifier, storage).await? {
+            Some(phc) => phc,
+            None => {
+                self.hash(config)?;
+
+                return Ok(SgStatusCode::Rejected);
+            },
+        };
+
+        match argon2::verify_encoded(&phc, self.0.expose_secret().as_bytes())? {
+            true => Ok(SgStatusCode::AuthenticPassphrase),
+            false => Ok(SgStatusCode::Rejected),
+        }
+    }
+
+    /// Verify the passphrase and, if the stored hash was made with parameters other than `config`,
+    /// replace it with a hash made with `config`. Returns the result of the verification
+    pub async fn rehash_if_needed(&self, identifier: &Identifier, storage: &dyn Storage, config: &Argon2Config) -> Result<SgStatusCode> {
+        let status = self.verify(identifier, storage, config).await?;
+
+        if let SgStatusCode::AuthenticPassphrase = status {
+            if let Some(phc) = Passphrase::stored_hash(identifier, storage).await? {
+                if Passphrase::needs_rehash(&phc, config) {
+                    let upgraded = self.hash(config)?;
+                    // Losing the race to a concurrent change leaves the newer hash in place
+                    storage.compare_and_swap(PASSPHRASE_DOCUMENT, identifier.storage_key().as_bytes(), Some(phc.as_bytes()), Some(upgraded.as_bytes())).await?;
+                }
+            }
+        }
+
+        Ok(status)
+    }
+
+    /// Verify the passphrase, upgrading its hash if needed, and on success issue a `CsprngToken` with `token` as its metadata,
+    /// so the session key is random and can not be derived from the identifier, time, role and lease.
+    /// Failed attempts are counted by `Lockout` and a locked identifier gets `SgStatusCode::Locked`
+    /// without the passphrase being checked. The issued token is returned as `LoginSecret::Session` and,
+    /// if this attempt places a lock that needs an unlock code, the code as `LoginSecret::UnlockCode`
+    pub async fn login(&self, identifier: &Identifier, token: PrngToken, storage: &dyn Storage, config: &Argon2Config, lockout: &LockoutPolicy) -> Result<(SgStatusCode, Option<LoginSecret>)> {
+        if let SgStatusCode::Locked = Lockout::check(identifier, storage).await? {
+            return Ok((SgStatusCode::Locked, None));
+        }
+
+        match self.rehash_if_needed(identifier, storage, config).await? {
+            SgStatusCode::AuthenticPassphrase => {
+                Lockout::record_success(identifier, storage).await?;
+                let issued = CsprngToken::new(token.identifier(identifier.clone())).issue(storage).await?;
+
+                Ok((SgStatusCode::AuthenticPassphrase, Some(LoginSecret::Session(issued))))
+            },
+            SgStatusCode::Rejected => {
+                let (status, code) = Lockout::record_failure(identifier, storage, lockout).await?;
+
+                Ok((status, code.map(LoginSecret::UnlockCode)))
+            },
+            status => Ok((status, None)),
+        }
+    }
+
+    /// Remove the passphrase of an identifier
+    pub async fn remove(identifier: &Identifier, storage: &dyn Storage) -> Result<SgStatusCode> {
+        match storage.delete(PASSPHRASE_DOCUMENT, identifier.storage_key().as_bytes()).await? {
+            true => Ok(SgStatusCode::Revoked),
+            false => Ok(SgStatusCode::Rejected),
+        }
+    }
+
+    /// Hash the passphrase into a PHC string with a fresh random salt
+    pub fn hash(&self, config: &Argon2Config) -> Result<String> {
+        let mut salt = [0_u8; PASSPHRASE_SALT_LEN];
+        getrandom::getrandom(&mut salt).map_err(|error| anyhow::anyhow!("{}", error))?;
+
+        Ok(argon2::hash_encoded(self.0.expose_secret().as_bytes(), &salt, &config.to_argon2())?)
+    }
+
+    /// Check whether a PHC string was made with parameters other than `config`
+    pub fn needs_rehash(phc: &str, config: &Argon2Config) -> bool {
+        // $argon2id$v=19$m=19456,t=2,p=1$<salt>$<hash>
+        let parts = phc.split('$').collect::<Vec<&str>>();
+        if parts.len() != 6 || parts[1] != "argon2id" || parts[2] != "v=19" {
+            return true;
+        }
+
+        let expected = format!("m={},t={},p={}", config.mem_cost, config.time_cost, config.lanes);
+        let hash_length = parts[5].len() * 3 / 4;
+
+        parts[3] != expected || hash_length != config.hash_length as usize
+    }
+
+    async fn stored_hash(identifier: &Identifier, storage: &dyn Storage) -> Result<Option<String>> {
+        match storage.get(PASSPHRASE_DOCUMENT, identifier.storage_key().as_bytes()).await? {
+            Some(data) => Ok(Some(String::from_utf8(data)?)),
+            None => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{MemPrngStore, TempLock};
+    use futures_lite::future::block_on;
+
+    fn fast_argon2() -> Argon2Config {
+        Argon2Config {
+            mem_cost: 64,
+            time_cost: 1,
+            lanes: 1,
+            hash_length: 16,
+        }
+    }
+
+    #[test]
+    fn login_keeps_unlock_codes_apart_from_session_tokens() {
+        let storage = MemPrngStore::new();
+        let config = fast_argon2();
+        let identifier = Identifier::new("user@example.com");
+        let policy = LockoutPolicy::default().threshold(1).unlock(TempLock::RandomToMail);
+
+        block_on(async {
+            Passphrase::new(SecretString::new("correct horse".into())).register(&identifier, &storage, &config).await.unwrap();
+
+            let correct = Passphrase::new(SecretString::new("correct horse".into()));
+            let (status, secret) = correct.login(&identifier, PrngToken::default(), &storage, &config, &policy).await.unwrap();
+            assert!(matches!(status, SgStatusCode::AuthenticPassphrase));
+            match secret {
+                Some(LoginSecret::Session(session)) => {
+                    assert!(CsprngToken::to_storage_key(session.expose_secret()).is_some());
+                    assert!(matches!(CsprngToken::authenticate(session.expose_secret(), &storage).await.unwrap(), SgStatusCode::AuthenticToken));
+                },
+                other => panic!("{:?}", other),
+            }
+
+            let wrong = Passphrase::new(SecretString::new("battery staple".into()));
+            let (status, secret) = wrong.login(&identifier, PrngToken::default(), &storage, &config, &policy).await.unwrap();
+            assert!(matches!(status, SgStatusCode::Locked));
+            assert!(matches!(secret, Some(LoginSecret::UnlockCode(_))));
+
+            let (status, secret) = correct.login(&identifier, PrngToken::default(), &storage, &config, &policy).await.unwrap();
+            assert!(matches!(status, SgStatusCode::Locked));
+            assert!(secret.is_none());
+        });
+    }
+}
@@ -0,0 +1,216 @@
+use crate::{global::{
+    SgStatusCode,
+    Identifier,
+    RECOVERY_DOCUMENT,
+}, storage::Storage, Argon2Config, Passphrase, Lockout, LockoutPolicy, LoginSecret};
+
+use data_encoding::BASE32_NOPAD;
+use secrecy::{SecretString, ExposeSecret};
+use zeroize::Zeroize;
+use anyhow::{Result, anyhow, bail};
+use serde::{Serialize, Deserialize};
+
+/// Number of recovery codes generated when the caller has no preference
+pub const RECOVERY_CODE_COUNT: usize = 10;
+/// Length in bytes of the randomness in a recovery code, 16 base32 characters
+pub const RECOVERY_CODE_LEN: usize = 10;
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct RecoveryRecord {
+    hashes: Vec<String>,
+}
+
+/// ### Single-use recovery codes that stand in for a second factor
+/// Codes look like `ABCD-EFGH-IJKL-MNOP`, dashes, whitespace and case are ignored when one is presented.
+/// Only the argon2id hash of each code is kept, under the `Identifier::storage_key()` in the `RECOVERY_DOCUMENT`,
+/// so checking a code costs up to one hash per unused code. Wrong codes are counted by the `Lockout` of the
+/// identifier so guesses are limited, the same as for passphrase logins and one-time passwords
+pub struct RecoveryCodes;
+
+impl RecoveryCodes {
+    /// Generate `count` new codes for an identifier, replacing and invalidating any previous set.
+    /// The codes are returned once to be shown to the user, they can not be recovered later
+    pub async fn generate(identifier: &Identifier, count: usize, storage: &dyn Storage, config: &Argon2Config) -> Result<Vec<SecretString>> {
+        if count == 0 {
+            bail!("At least one recovery code must be generated");
+        }
+
+        let mut codes = Vec::with_capacity(count);
+        let mut record = RecoveryRecord::default();
+
+        for _ in 0..count {
+            let mut raw = [0_u8; RECOVERY_CODE_LEN];
+            getrandom::getrandom(&mut raw).map_err(|error| anyhow!("{}", error))?;
+
+            let mut encoded = BASE32_NOPAD.encode(&raw);
+            raw.zeroize();
+
+            let code = encoded.as_bytes()
+                .chunks(4)
+                .map(|group| String::from_utf8_lossy(group).into_owned())
+                .collect::<Vec<String>>()
+                .join("-");
+
+            record.hashes.push(Passphrase::new(SecretString::new(encoded.clone())).hash(config)?);
+            encoded.zeroize();
+
+            codes.push(SecretString::new(code));
+        }
+
+        let data = bincode::serialize::<RecoveryRecord>(&record)?;
+        storage.put(RECOVERY_DOCUMENT, identifier.storage_key().as_bytes(), &data).await?;
+
+        Ok(codes)
+    }
+
+    /// Use a recovery code, it can not be used again. A wrong code is counted as a failed attempt against `lockout`.
+    /// Returns `SgStatusCode::RecoveryCodeUsed` with the number of codes left, `SgStatusCode::Locked` while the
+    /// identifier is locked or `SgStatusCode::Rejected` if the code does not match an unused code of the identifier.
+    /// If a wrong code places a lock that needs an unlock code, the code is returned as `LoginSecret::UnlockCode`
+    pub async fn consume(identifier: &Identifier, code: &SecretString, lockout: &LockoutPolicy, storage: &dyn Storage) -> Result<(SgStatusCode, Option<LoginSecret>)> {
+        if let SgStatusCode::Locked = Lockout::check(identifier, storage).await? {
+            return Ok((SgStatusCode::Locked, None));
+        }
+
+        match RecoveryCodes::use_code(identifier, code, storage).await? {
+            SgStatusCode::AccessDenied => match Lockout::record_failure(identifier, storage, lockout).await? {
+                (SgStatusCode::Locked, code) => Ok((SgStatusCode::Locked, code.map(LoginSecret::UnlockCode))),
+                _ => Ok((SgStatusCode::Rejected, None)),
+            },
+            status @ SgStatusCode::RecoveryCodeUsed(_) => {
+                Lockout::record_success(identifier, storage).await?;
+
+                Ok((status, None))
+            },
+            status => Ok((status, None)),
+        }
+    }
+
+    /// Remove a matching code. Returns `SgStatusCode::AccessDenied` if no unused code matches
+    /// and `SgStatusCode::Rejected` if the identifier has no codes
+    async fn use_code(identifier: &Identifier, code: &SecretString, storage: &dyn Storage) -> Result<SgStatusCode> {
+        let key = identifier.storage_key();
+        let presented = SecretString::new(RecoveryCodes::normalize(code.expose_secret()));
+
+        loop {
+            let (data, mut record) = match RecoveryCodes::fetch(identifier, storage).await? {
+                Some(found) => found,
+                None => return Ok(SgStatusCode::Rejected),
+            };
+
+            let mut matched = None;
+            for (index, phc) in record.hashes.iter().enumerate() {
+                if argon2::verify_encoded(phc, presented.expose_secret().as_bytes())? {
+                    matched = Some(index);
+                    break;
+                }
+            }
+
+            match matched {
+                Some(index) => { record.hashes.remove(index); },
+                None => return Ok(SgStatusCode::AccessDenied),
+            }
+
+            let updated = bincode::serialize::<RecoveryRecord>(&record)?;
+
+            // A concurrent use of the same code makes this fail and the retry no longer finds it
+            if storage.compare_and_swap(RECOVERY_DOCUMENT, key.as_bytes(), Some(&data), Some(&updated)).await? {
+                return Ok(SgStatusCode::RecoveryCodeUsed(record.hashes.len()));
+            }
+        }
+    }
+
+    /// The number of unused codes of an identifier
+    pub async fn remaining(identifier: &Identifier, storage: &dyn Storage) -> Result<usize> {
+        Ok(RecoveryCodes::fetch(identifier, storage).await?
+            .map_or(0, |(_, record)| record.hashes.len()))
+    }
+
+    /// Remove every code of an identifier
+    pub async fn remove(identifier: &Identifier, storage: &dyn Storage) -> Result<SgStatusCode> {
+        match storage.delete(RECOVERY_DOCUMENT, identifier.storage_key().as_bytes()).await? {
+            true => Ok(SgStatusCode::Revoked),
+            false => Ok(SgStatusCode::Rejected),
+        }
+    }
+
+    fn normalize(code: &str) -> String {
+        code.chars()
+            .filter(|character| *character != '-' && !character.is_whitespace())
+            .map(|character| character.to_ascii_uppercase())
+            .collect()
+    }
+
+    async fn fetch(identifier: &Identifier, storage: &dyn Storage) -> Result<Option<(Vec<u8>, RecoveryRecord)>> {
+        match storage.get(RECOVERY_DOCUMENT, identifier.storage_key().as_bytes()).await? {
+            Some(data) => {
+                let record = bincode::deserialize::<RecoveryRecord>(&data)?;
+
+                Ok(Some((data, record)))
+            },
+            None => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{MemPrngStore, TempLock};
+    use futures_lite::future::block_on;
+
+    fn fast_argon2() -> Argon2Config {
+        Argon2Config {
+            mem_cost: 64,
+            time_cost: 1,
+            lanes: 1,
+            hash_length: 16,
+        }
+    }
+
+    #[test]
+    fn recovery_codes_are_single_use() {
+        let storage = MemPrngStore::new();
+        let identifier = Identifier::new("user@example.com");
+        let lockout = LockoutPolicy::default();
+
+        block_on(async {
+            let codes = RecoveryCodes::generate(&identifier, 3, &storage, &fast_argon2()).await.unwrap();
+            assert_eq!(RecoveryCodes::remaining(&identifier, &storage).await.unwrap(), 3);
+
+            // Dashes and case are ignored
+            let presented = SecretString::new(codes[1].expose_secret().replace('-', "").to_lowercase());
+            assert!(matches!(RecoveryCodes::consume(&identifier, &presented, &lockout, &storage).await.unwrap(), (SgStatusCode::RecoveryCodeUsed(2), None)));
+
+            assert!(matches!(RecoveryCodes::consume(&identifier, &codes[1], &lockout, &storage).await.unwrap(), (SgStatusCode::Rejected, None)));
+            assert_eq!(RecoveryCodes::remaining(&identifier, &storage).await.unwrap(), 2);
+
+            assert!(matches!(RecoveryCodes::consume(&identifier, &codes[0], &lockout, &storage).await.unwrap(), (SgStatusCode::RecoveryCodeUsed(1), None)));
+        });
+    }
+
+    #[test]
+    fn wrong_codes_lock_the_identifier() {
+        let storage = MemPrngStore::new();
+        let identifier = Identifier::new("user@example.com");
+        let lockout = LockoutPolicy::default().threshold(2).unlock(TempLock::RandomToMail);
+
+        block_on(async {
+            let codes = RecoveryCodes::generate(&identifier, 2, &storage, &fast_argon2()).await.unwrap();
+            let wrong = SecretString::new("AAAA-AAAA-AAAA-AAAA".into());
+
+            let (status, unlock) = RecoveryCodes::consume(&identifier, &wrong, &lockout, &storage).await.unwrap();
+            assert!(matches!(status, SgStatusCode::Rejected));
+            assert!(unlock.is_none());
+
+            let (status, unlock) = RecoveryCodes::consume(&identifier, &wrong, &lockout, &storage).await.unwrap();
+            assert!(matches!(status, SgStatusCode::Locked));
+            assert!(matches!(unlock, Some(LoginSecret::UnlockCode(_))));
+
+            // A valid code is not even checked while the identifier is locked
+            let (status, _) = RecoveryCodes::consume(&identifier, &codes[0], &lockout, &storage).await.unwrap();
+            assert!(matches!(status, SgStatusCode::Locked));
+            assert_eq!(RecoveryCodes::remaining(&identifier, &storage).await.unwrap(), 2);
+        });
+    }
+}
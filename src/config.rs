@@ -0,0 +1,411 @@
+use crate::{global::{CONFIG_FILE, TOKEN_DB_PATH, Lease, Role, SgStatusCode}, RoleHierarchy, PortGuard, SchemeKind, OtpAlgorithm};
+
+use tai64::TAI64N;
+use anyhow::{Result, Context, bail, ensure};
+use serde::{Serialize, Deserialize};
+use std::{path::{Path, PathBuf}, time::Duration};
+
+/// The longest `lease.default_hours` accepted, ten years
+pub const LEASE_MAX_HOURS: u64 = 87_600;
+/// The longest `lockout.lock_secs` accepted, one year
+pub const LOCKOUT_MAX_SECS: u64 = 31_536_000;
+/// The widest `otp.window` accepted
+pub const OTP_MAX_WINDOW: u64 = 10;
+
+/// ### The engine configuration
+/// Loaded from `SchemeGuardianConf.toml`, every section and field is optional and
+/// falls back to the defaults documented on each field.
+/// #### Example
+/// ```toml
+/// [storage]
+/// db_path = "../TuringDB_Repo/TokenStorage"
+///
+/// [lease]
+/// default_hours = 24
+///
+/// [argon2]
+/// mem_cost = 19456
+/// time_cost = 2
+/// lanes = 1
+/// hash_length = 32
+///
+/// [gc]
+/// interval_secs = 300
+///
+/// [lockout]
+/// threshold = 5
+/// lock_secs = 900
+///
+/// [otp]
+/// issuer = "SchemeGuardian"
+/// algorithm = "sha1"
+/// digits = 6
+/// period_secs = 30
+/// window = 1
+///
+/// [[roles]]
+/// name = "Auditor"
+/// inherits = "User"
+/// inherited_by = "SubAdmin"
+///
+/// [[ports]]
+/// port = 8443
+/// schemes = ["https", "cookie"]
+/// ```
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SgConfig {
+    pub storage: StorageConfig,
+    pub lease: LeaseConfig,
+    pub argon2: Argon2Config,
+    pub gc: GcConfig,
+    pub lockout: LockoutConfig,
+    pub otp: OtpConfig,
+    pub roles: Vec<RoleConfig>,
+    pub ports: Vec<PortConfig>,
+    /// Where the configuration was loaded from
+    #[serde(skip)]
+    source: Option<PathBuf>,
+    /// `blake3` hash of the file the configuration was loaded from
+    #[serde(skip)]
+    fingerprint: Option<[u8; blake3::OUT_LEN]>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageConfig {
+    /// Path of the TuringDB database holding the tokens. Defaults to `TOKEN_DB_PATH`
+    pub db_path: PathBuf,
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        Self { db_path: TOKEN_DB_PATH.into() }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LeaseConfig {
+    /// Hours until a `Lease::DateExpiryTAI` issued by default expires, at most `LEASE_MAX_HOURS`. Defaults to `24`
+    pub default_hours: u64,
+}
+
+impl Default for LeaseConfig {
+    fn default() -> Self {
+        Self { default_hours: 24 }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Argon2Config {
+    /// Memory in KiB, at most 4 GiB. Defaults to `19456`
+    pub mem_cost: u32,
+    /// Number of passes, at most `64`. Defaults to `2`
+    pub time_cost: u32,
+    /// Degree of parallelism, at most `64`. Defaults to `1`
+    pub lanes: u32,
+    /// Length in bytes of the hash, at most `64`. Defaults to `32`
+    pub hash_length: u32,
+}
+
+impl Default for Argon2Config {
+    fn default() -> Self {
+        Self {
+            mem_cost: 19456,
+            time_cost: 2,
+            lanes: 1,
+            hash_length: 32,
+        }
+    }
+}
+
+impl Argon2Config {
+    /// The `rust-argon2` configuration for argon2id with these parameters
+    pub fn to_argon2(&self) -> argon2::Config<'static> {
+        argon2::Config {
+            variant: argon2::Variant::Argon2id,
+            version: argon2::Version::Version13,
+            mem_cost: self.mem_cost,
+            time_cost: self.time_cost,
+            lanes: self.lanes,
+            thread_mode: argon2::ThreadMode::Sequential,
+            secret: &[],
+            ad: &[],
+            hash_length: self.hash_length,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GcConfig {
+    /// Seconds between garbage collector sweeps. Defaults to `300`
+    pub interval_secs: u64,
+}
+
+impl Default for GcConfig {
+    fn default() -> Self {
+        Self { interval_secs: 300 }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LockoutConfig {
+    /// Failed authentication attempts before an identifier is locked. Defaults to `5`
+    pub threshold: u32,
+    /// Seconds a `TempLock::Duration` lasts, at most `LOCKOUT_MAX_SECS`. Defaults to `900`
+    pub lock_secs: u64,
+}
+
+impl Default for LockoutConfig {
+    fn default() -> Self {
+        Self {
+            threshold: 5,
+            lock_secs: 900,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OtpConfig {
+    /// The issuer shown by authenticator apps. Defaults to `SchemeGuardian`
+    pub issuer: String,
+    /// HMAC algorithm of one-time passwords. Defaults to `sha1`, the only one most authenticator apps support
+    pub algorithm: OtpAlgorithm,
+    /// Number of digits in a one-time password. Defaults to `6`
+    pub digits: u32,
+    /// Seconds a TOTP code is valid for. Defaults to `30`
+    pub period_secs: u64,
+    /// Codes either side of the current TOTP time step, or ahead of the HOTP counter, that are accepted, at most `OTP_MAX_WINDOW`. Defaults to `1`
+    pub window: u64,
+}
+
+impl Default for OtpConfig {
+    fn default() -> Self {
+        Self {
+            issuer: "SchemeGuardian".into(),
+            algorithm: OtpAlgorithm::Sha1,
+            digits: 6,
+            period_secs: 30,
+            window: 1,
+        }
+    }
+}
+
+/// A custom `Role::Specifed` and where it sits in the role hierarchy
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RoleConfig {
+    /// The name of the `Role::Specifed`
+    pub name: String,
+    /// The role whose permissions this role inherits
+    pub inherits: Option<String>,
+    /// The role that inherits the permissions of this role
+    pub inherited_by: Option<String>,
+}
+
+/// The schemes allowed on a port, see `PortGuard`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PortConfig {
+    pub port: u16,
+    pub schemes: Vec<SchemeKind>,
+}
+
+impl SgConfig {
+    /// Load the configuration from `CONFIG_FILE`
+    pub async fn load_default() -> Result<SgConfig> {
+        SgConfig::load(CONFIG_FILE.as_ref()).await
+    }
+
+    /// Load the configuration from a TOML file.
+    /// A missing file gives the default configuration, an invalid file is an error
+    /// The `blake3` fingerprint of the file is kept so that `check_tampering()` can detect later changes
+    pub async fn load(path: &Path) -> Result<SgConfig> {
+        let mut config = match SgConfig::read(path).await? {
+            Some(contents) => {
+                let mut config = std::str::from_utf8(&contents)
+                    .map_err(anyhow::Error::from)
+                    .and_then(SgConfig::from_toml)
+                    .with_context(|| format!("Invalid SchemeGuardian configuration at `{}`", path.display()))?;
+                config.fingerprint = Some(*blake3::hash(&contents).as_bytes());
+
+                config
+            },
+            None => SgConfig::default(),
+        };
+        config.source = Some(path.to_path_buf());
+
+        Ok(config)
+    }
+
+    /// Compare the file the configuration was loaded from with the fingerprint taken when it was loaded.
+    /// Returns `SgStatusCode::ConfigTampered` if the file was changed, created or removed since then
+    pub async fn check_tampering(&self) -> Result<SgStatusCode> {
+        let path = match &self.source {
+            Some(path) => path,
+            None => return Ok(SgStatusCode::ConfigIntact),
+        };
+
+        let current = SgConfig::read(path).await?.map(|contents| *blake3::hash(&contents).as_bytes());
+
+        // `blake3::Hash` compares in constant time
+        let intact = match (current, self.fingerprint) {
+            (Some(current), Some(loaded)) => blake3::Hash::from(current) == blake3::Hash::from(loaded),
+            (None, None) => true,
+            _ => false,
+        };
+
+        match intact {
+            true => Ok(SgStatusCode::ConfigIntact),
+            false => Ok(SgStatusCode::ConfigTampered),
+        }
+    }
+
+    /// The `blake3` fingerprint of the loaded file, `None` if the defaults were used
+    pub fn fingerprint(&self) -> Option<blake3::Hash> {
+        self.fingerprint.map(blake3::Hash::from)
+    }
+
+    async fn read(path: &Path) -> Result<Option<Vec<u8>>> {
+        match async_fs::read(path).await {
+            Ok(contents) => Ok(Some(contents)),
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(error) => Err(error)
+                .with_context(|| format!("Unable to read SchemeGuardian configuration at `{}`", path.display())),
+        }
+    }
+
+    /// Parse and validate the configuration from a TOML string
+    pub fn from_toml(contents: &str) -> Result<SgConfig> {
+        let config = toml::from_str::<SgConfig>(contents)?;
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    /// Check that the values are usable
+    pub fn validate(&self) -> Result<()> {
+        ensure!(!self.storage.db_path.as_os_str().is_empty(), "`storage.db_path` must not be empty");
+        ensure!(self.lease.default_hours > 0, "`lease.default_hours` must be greater than 0");
+        ensure!(self.lease.default_hours <= LEASE_MAX_HOURS, "`lease.default_hours` must be at most {}", LEASE_MAX_HOURS);
+
+        ensure!((1..=64).contains(&self.argon2.lanes), "`argon2.lanes` must be between 1 and 64");
+        ensure!(
+            self.argon2.lanes.checked_mul(8).map_or(false, |minimum| self.argon2.mem_cost >= minimum),
+            "`argon2.mem_cost` must be at least 8 KiB per lane"
+        );
+        ensure!(self.argon2.mem_cost <= 4 * 1024 * 1024, "`argon2.mem_cost` must be at most 4 GiB");
+        ensure!((1..=64).contains(&self.argon2.time_cost), "`argon2.time_cost` must be between 1 and 64");
+        ensure!((16..=64).contains(&self.argon2.hash_length), "`argon2.hash_length` must be between 16 and 64 bytes");
+
+        ensure!(self.gc.interval_secs > 0, "`gc.interval_secs` must be greater than 0");
+        ensure!(self.lockout.threshold > 0, "`lockout.threshold` must be greater than 0");
+        ensure!(self.lockout.lock_secs > 0, "`lockout.lock_secs` must be greater than 0");
+        ensure!(self.lockout.lock_secs <= LOCKOUT_MAX_SECS, "`lockout.lock_secs` must be at most {}", LOCKOUT_MAX_SECS);
+        ensure!(!self.otp.issuer.trim().is_empty(), "`otp.issuer` must not be empty");
+        ensure!(!self.otp.issuer.contains(':'), "`otp.issuer` must not contain `:`");
+        ensure!((6..=8).contains(&self.otp.digits), "`otp.digits` must be between 6 and 8");
+        ensure!(self.otp.period_secs > 0, "`otp.period_secs` must be greater than 0");
+        ensure!(self.otp.window <= OTP_MAX_WINDOW, "`otp.window` must be at most {}", OTP_MAX_WINDOW);
+
+        for (index, role) in self.roles.iter().enumerate() {
+            if role.name.trim().is_empty() {
+                bail!("`roles[{}].name` must not be empty", index);
+            }
+            if Role::from_name(&role.name).is_builtin() {
+                bail!("`roles[{}].name` `{}` is a builtin role", index, role.name);
+            }
+            if self.roles[..index].iter().any(|previous| previous.name == role.name) {
+                bail!("`roles[{}].name` `{}` is defined more than once", index, role.name);
+            }
+        }
+
+        RoleHierarchy::from_config(self).context("Invalid `roles`")?;
+        PortGuard::from_config(self).context("Invalid `ports`")?;
+
+        Ok(())
+    }
+
+    /// The `Lease` given to tokens that do not set one, see `PrngToken::from_config()`
+    pub fn default_lease(&self) -> Lease {
+        let seconds = self.lease.default_hours.min(LEASE_MAX_HOURS) * 3600;
+
+        Lease::DateExpiryTAI(TAI64N::now() + Duration::from_secs(seconds))
+    }
+
+    /// Time between garbage collector sweeps
+    pub fn gc_interval(&self) -> Duration {
+        Duration::from_secs(self.gc.interval_secs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_lite::future::block_on;
+
+    fn config_file(name: &str, contents: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("sg-{}-{}.toml", name, std::process::id()));
+        std::fs::write(&path, contents).unwrap();
+
+        path
+    }
+
+    #[test]
+    fn validate_rejects_values_that_would_overflow() {
+        let mut config = SgConfig::default();
+        config.argon2.lanes = u32::MAX;
+        assert!(config.validate().is_err());
+
+        let mut config = SgConfig::default();
+        config.lease.default_hours = u64::MAX;
+        assert!(config.validate().is_err());
+        assert!(matches!(config.default_lease(), Lease::DateExpiryTAI(_)));
+
+        assert!(SgConfig::default().validate().is_ok());
+    }
+
+    #[test]
+    fn load_rejects_configurations_that_break_the_invariants() {
+        block_on(async {
+            let path = config_file("invariants", "[[ports]]\nport = 443\nschemes = [\"http\"]\n");
+            assert!(SgConfig::load(&path).await.is_err());
+
+            std::fs::write(&path, "[[ports]]\nport = 8443\nschemes = [\"https\"]\n").unwrap();
+            let config = SgConfig::load(&path).await.unwrap();
+            assert_eq!(config.ports.len(), 1);
+            assert!(config.fingerprint().is_some());
+
+            std::fs::remove_file(&path).unwrap();
+        });
+    }
+
+    #[test]
+    fn check_tampering_detects_changes_to_the_loaded_file() {
+        block_on(async {
+            let path = config_file("tampering", "[lease]\ndefault_hours = 24\n");
+            let config = SgConfig::load(&path).await.unwrap();
+            assert!(matches!(config.check_tampering().await.unwrap(), SgStatusCode::ConfigIntact));
+
+            std::fs::write(&path, "[lease]\ndefault_hours = 8760\n").unwrap();
+            assert!(matches!(config.check_tampering().await.unwrap(), SgStatusCode::ConfigTampered));
+
+            std::fs::remove_file(&path).unwrap();
+            assert!(matches!(config.check_tampering().await.unwrap(), SgStatusCode::ConfigTampered));
+
+            // Loaded without a file, creating one later is tampering too
+            let config = SgConfig::load(&path).await.unwrap();
+            assert!(config.fingerprint().is_none());
+            assert!(matches!(config.check_tampering().await.unwrap(), SgStatusCode::ConfigIntact));
+            std::fs::write(&path, "").unwrap();
+            assert!(matches!(config.check_tampering().await.unwrap(), SgStatusCode::ConfigTampered));
+
+            std::fs::remove_file(&path).unwrap();
+        });
+    }
+}
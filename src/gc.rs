@@ -0,0 +1,190 @@
+use crate::{global::{
+    GcExec,
+    Lease,
+    TOKEN_SESSION_DOCUMENT,
+    GC_REGISTRY,
+    GC_STORAGE,
+    REVOCATION_DOCUMENT,
+}, storage::Storage, PrngToken};
+
+use async_io::Timer;
+use tai64::TAI64N;
+use anyhow::Result;
+use serde::{Serialize, Deserialize};
+use std::{sync::Arc, time::Duration};
+
+/// The key in `GC_STORAGE` holding the `GcReport` of the last sweep
+pub const GC_LAST_SWEEP: &[u8] = b"LastSweep";
+
+/// ### Statistics of a single garbage collector sweep
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GcReport {
+    /// Number of `GcExec::Hit`
+    pub hits: usize,
+    /// Number of `GcExec::Miss`
+    pub misses: usize,
+    /// Number of `GcExec::MalformedOperation`
+    pub malformed: usize,
+}
+
+impl GcReport {
+    pub fn record(&mut self, outcome: GcExec) {
+        match outcome {
+            GcExec::Hit => self.hits += 1,
+            GcExec::Miss => self.misses += 1,
+            GcExec::MalformedOperation => self.malformed += 1,
+        }
+    }
+}
+
+/// ### Removes expired and corrupted tokens from the session document
+/// Tokens with a `Lease::DateExpiryTAI` are indexed by expiry in the `GC_REGISTRY` when they are stored,
+/// so a sweep only has to decode the lease headers there to find expired tokens.
+/// The session document itself is then scanned for `Lease::Corrupted` and undecodable records and
+/// entries of the `REVOCATION_DOCUMENT` are dropped once the lease of the revoked token has passed.
+/// The report of the last sweep is kept in `GC_STORAGE`
+pub struct GarbageCollector {
+    storage: Arc<dyn Storage>,
+    interval: Duration,
+}
+
+impl GarbageCollector {
+    pub fn new(storage: Arc<dyn Storage>, interval: Duration) -> Self {
+        Self { storage, interval }
+    }
+
+    /// Sweep every `interval`, this never returns unless the storage fails
+    pub async fn run(&self) -> Result<()> {
+        loop {
+            Timer::after(self.interval).await;
+            self.gc_now().await?;
+        }
+    }
+
+    /// Run a sweep immediately
+    pub async fn gc_now(&self) -> Result<GcReport> {
+        gc_now(self.storage.as_ref()).await
+    }
+
+    /// The report of the last sweep if there has been one
+    pub async fn last_report(&self) -> Result<Option<GcReport>> {
+        match self.storage.get(GC_STORAGE, GC_LAST_SWEEP).await? {
+            Some(data) => Ok(Some(bincode::deserialize::<GcReport>(&data)?)),
+            None => Ok(None),
+        }
+    }
+}
+
+/// Run a single garbage collector sweep over `storage`
+pub async fn gc_now(storage: &dyn Storage) -> Result<GcReport> {
+    let mut report = GcReport::default();
+    let now = TAI64N::now();
+
+    for (key, header) in storage.scan(GC_REGISTRY).await? {
+        if header.is_empty() {
+            storage.delete(GC_REGISTRY, &key).await?;
+            report.record(GcExec::MalformedOperation);
+
+            continue;
+        }
+
+        match Lease::from_header(&header) {
+            Lease::DateExpiryTAI(expiry) if expiry > now => continue,
+            Lease::DateExpiryTAI(_) => {
+                storage.delete(GC_REGISTRY, &key).await?;
+
+                match storage.delete(TOKEN_SESSION_DOCUMENT, &key).await? {
+                    true => report.record(GcExec::Hit),
+                    false => report.record(GcExec::Miss),
+                }
+            },
+            _ => {
+                storage.delete(GC_REGISTRY, &key).await?;
+                report.record(GcExec::MalformedOperation);
+            },
+        }
+    }
+
+    for (key, data) in storage.scan(TOKEN_SESSION_DOCUMENT).await? {
+        let outcome = match bincode::deserialize::<PrngToken>(&data) {
+            Ok(token) => match token.get_lease() {
+                Lease::Corrupted => GcExec::Hit,
+                lease if lease.is_expired() => GcExec::Hit,
+                _ => continue,
+            },
+            Err(_) => GcExec::MalformedOperation,
+        };
+
+        // Only remove the record that was inspected in case it was replaced in the meantime
+        if storage.compare_and_swap(TOKEN_SESSION_DOCUMENT, &key, Some(&data), None).await? {
+            storage.delete(GC_REGISTRY, &key).await?;
+            report.record(outcome);
+        }
+    }
+
+    // Revoked stateless tokens only need to be remembered until their lease would have ended anyway
+    for (key, header) in storage.scan(REVOCATION_DOCUMENT).await? {
+        let outcome = match header.is_empty() {
+            true => GcExec::MalformedOperation,
+            false => match Lease::from_header(&header) {
+                Lease::DateExpiryTAI(expiry) if expiry <= now => GcExec::Hit,
+                Lease::Corrupted => GcExec::MalformedOperation,
+                _ => continue,
+            },
+        };
+
+        if storage.compare_and_swap(REVOCATION_DOCUMENT, &key, Some(&header), None).await? {
+            report.record(outcome);
+        }
+    }
+
+    storage.put(GC_STORAGE, GC_LAST_SWEEP, &bincode::serialize::<GcReport>(&report)?).await?;
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MemPrngStore;
+    use futures_lite::future::block_on;
+    use std::time::UNIX_EPOCH;
+
+    #[test]
+    fn gc_now_reports_every_outcome() {
+        let storage = Arc::new(MemPrngStore::new());
+        let collector = GarbageCollector::new(storage.clone(), Duration::from_secs(60));
+        let expired = Lease::DateExpiryTAI(TAI64N::from_system_time(&UNIX_EPOCH));
+
+        block_on(async {
+            // Hits, an expired token, a corrupted token and a revocation that has passed
+            let token = PrngToken::default().lease(expired.clone());
+            token.store(&token.token_hash(), storage.as_ref()).await.unwrap();
+            let token = PrngToken::default().lease(Lease::Corrupted);
+            token.store(&token.token_hash(), storage.as_ref()).await.unwrap();
+            storage.put(REVOCATION_DOCUMENT, b"revoked", &Lease::to_header(&expired)).await.unwrap();
+
+            // A miss, a registry entry whose token is gone
+            storage.put(GC_REGISTRY, b"gone", &Lease::to_header(&expired)).await.unwrap();
+
+            // Malformed, an empty registry header and an undecodable token
+            storage.put(GC_REGISTRY, b"empty", &[]).await.unwrap();
+            storage.put(TOKEN_SESSION_DOCUMENT, b"junk", &[0xff]).await.unwrap();
+
+            // Kept, a live token and a revocation that never ends
+            let token = PrngToken::default().role(crate::global::Role::Admin);
+            token.store(&token.token_hash(), storage.as_ref()).await.unwrap();
+            storage.put(REVOCATION_DOCUMENT, b"forever", &Lease::to_header(&Lease::Lifetime)).await.unwrap();
+
+            let report = collector.gc_now().await.unwrap();
+
+            assert_eq!(report, GcReport { hits: 3, misses: 1, malformed: 2 });
+            assert_eq!(collector.last_report().await.unwrap(), Some(report));
+            assert_eq!(storage.len(TOKEN_SESSION_DOCUMENT).await, 1);
+            assert_eq!(storage.len(REVOCATION_DOCUMENT).await, 1);
+            assert!(storage.is_empty(GC_REGISTRY).await);
+
+            assert_eq!(collector.gc_now().await.unwrap(), GcReport::default());
+        });
+    }
+}
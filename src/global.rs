@@ -8,6 +8,12 @@ use secrecy::{SecretString, ExposeSecret};
 pub (crate) const CONFIG_FILE: &str = "./SchemeGuardian/SchemeGuardianConf.toml";
 pub const TOKEN_DB_PATH: &str = "../TuringDB_Repo/TokenStorage";
 pub const TOKEN_SESSION_DOCUMENT: &str = "../TuringDB_Repo/TokenStorage/SessionStorage";
+pub const PASSPHRASE_DOCUMENT: &str = "PassphraseStorage";
+pub const ACCOUNT_DOCUMENT: &str = "AccountStorage";
+pub const LOCKOUT_DOCUMENT: &str = "LockoutStorage";
+pub const REVOCATION_DOCUMENT: &str = "RevocationStorage";
+pub const OTP_DOCUMENT: &str = "OtpStorage";
+pub const RECOVERY_DOCUMENT: &str = "RecoveryStorage";
 pub (crate) const GC_REGISTRY: &str = "GcRegistry";
 pub (crate) const GC_STORAGE: &str = "GcStorage";
 pub (crate) type TimeStamp = TAI64N;
@@ -20,7 +26,7 @@ pub (crate) type TimeStamp = TAI64N;
 /// assert_eq!(foo, Lease::Lifetime);
 /// ```
 #[derive(PartialEq, PartialOrd, Clone, Eq, Zeroize, Debug, Serialize, Deserialize)]
-pub (crate) enum Lease {
+pub enum Lease {
     /// This is a lease to a secret that will never expire. This is field not recommended
     Lifetime,
     /// Has an expiry TAI time TAI64N type which doesnt care about leap seconds
@@ -48,6 +54,56 @@ impl Default for Lease {
 }
 
 impl<'l> Lease {
+    /// Check whether a `Lease::DateExpiryTAI` has passed by comparing it with the current `TAI64N` time.
+    /// Leases that are not bound to a date never expire here
+    pub fn is_expired(&self) -> bool {
+        match self {
+            Lease::DateExpiryTAI(expiry) => TAI64N::now() >= *expiry,
+            _ => false,
+        }
+    }
+    /// Stateless tokens store nothing to count usage with, so only `Lease::Lifetime` and `Lease::DateExpiryTAI` can be used
+    pub fn ensure_stateless(&self) -> Result<()> {
+        match self {
+            Lease::Lifetime | Lease::DateExpiryTAI(_) => Ok(()),
+            lease => anyhow::bail!("`{:?}` can not be used with a stateless token", lease),
+        }
+    }
+    /// The status of an authentic stateless token with this lease, `SgStatusCode::Expired` once a
+    /// `Lease::DateExpiryTAI` has passed and `SgStatusCode::Rejected` for leases a stateless token can not have
+    pub fn stateless_status(&self) -> SgStatusCode {
+        match self {
+            Lease::Lifetime => SgStatusCode::AuthenticToken,
+            lease @ Lease::DateExpiryTAI(_) if lease.is_expired() => SgStatusCode::Expired,
+            Lease::DateExpiryTAI(_) => SgStatusCode::AuthenticToken,
+            _ => SgStatusCode::Rejected,
+        }
+    }
+    /// Work out what a `Usage` does to this lease.
+    /// Time based leases are not affected by usage, only the counted and one-time leases are
+    pub fn next_use(&self, usage: Usage) -> LeaseUse {
+        match (self, usage) {
+            (Lease::Corrupted, _) => LeaseUse::Denied,
+            (Lease::FirstAccess, _) => LeaseUse::Spent,
+            (Lease::OnDownload, Usage::Download) => LeaseUse::Spent,
+            (Lease::OnUpload, Usage::Upload) => LeaseUse::Spent,
+            (Lease::OnDownloads(remaining), Usage::Download) |
+            (Lease::OnUploads(remaining), Usage::Upload) => {
+                match *remaining {
+                    0 => LeaseUse::Denied,
+                    1 => LeaseUse::Spent,
+                    count => match self {
+                        Lease::OnDownloads(_) => LeaseUse::Remaining(Lease::OnDownloads(count - 1)),
+                        _ => LeaseUse::Remaining(Lease::OnUploads(count - 1)),
+                    },
+                }
+            },
+            (Lease::OnDownload, Usage::Upload) | (Lease::OnDownloads(_), Usage::Upload) => LeaseUse::Denied,
+            (Lease::OnUpload, Usage::Download) | (Lease::OnUploads(_), Usage::Download) => LeaseUse::Denied,
+            _ => LeaseUse::Unchanged,
+        }
+    }
+
     pub fn to_header(value: &Lease) -> Vec<u8> {
         match value {
             &Lease::Lifetime => vec![0x00],
@@ -112,7 +168,31 @@ impl<'l> Lease {
     }
 }
 
-#[derive(PartialEq, PartialOrd, Clone, Eq, Zeroize, Debug, Serialize, Deserialize)]
+/// The kind of access a token is used for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Usage {
+    /// Any authorized access
+    Access,
+    /// A download of a resource
+    Download,
+    /// An upload of a resource
+    Upload,
+}
+
+/// The outcome of using a `Lease` once
+#[derive(Debug, PartialEq, Eq)]
+pub enum LeaseUse {
+    /// The lease does not allow this usage
+    Denied,
+    /// The usage is allowed and the lease stays as it is
+    Unchanged,
+    /// The usage is allowed and it was the last one the lease allows so the token must be revoked
+    Spent,
+    /// The usage is allowed and the lease must be replaced with the remaining count
+    Remaining(Lease),
+}
+
+#[derive(PartialEq, PartialOrd, Clone, Eq, Hash, Zeroize, Debug, Serialize, Deserialize)]
 pub enum Role {
     SuperUser,
     Admin,
@@ -139,6 +219,36 @@ impl Role {
         }
     }
 
+    /// Get a role from its name, names that are not builtin give a `Role::Specifed`
+    pub fn from_name(name: &str) -> Role {
+        match name {
+            "SuperUser" => Role::SuperUser,
+            "Admin" => Role::Admin,
+            "SubAdmin" => Role::SubAdmin,
+            "User" => Role::User,
+            custom => Role::Specifed(custom.into()),
+        }
+    }
+
+    /// The name of the role as used in the configuration
+    pub fn name(&self) -> &str {
+        match self {
+            Role::SuperUser => "SuperUser",
+            Role::Admin => "Admin",
+            Role::SubAdmin => "SubAdmin",
+            Role::User => "User",
+            Role::Specifed(custom) => custom.as_str(),
+        }
+    }
+
+    /// Check whether this is one of the builtin roles
+    pub fn is_builtin(&self) -> bool {
+        match self {
+            Role::Specifed(_) => false,
+            _ => true,
+        }
+    }
+
     pub fn from_header(value: &[u8]) -> Role {
         match value {
             &[0x00] => Role::SuperUser,
@@ -176,11 +286,25 @@ impl TaiTimestamp {
     pub fn get_bytes(&self) -> secrecy::SecretVec<u8> {
         secrecy::SecretVec::new(self.0.to_bytes().to_vec())
     }
+    pub fn get_tai(&self) -> TAI64N {
+        self.0
+    }
 }
 
 #[derive(Zeroize, Clone, Serialize, Deserialize)]
 pub struct Identifier(pub (crate) String);
 
+impl Identifier {
+    pub fn new(value: &str) -> Self {
+        Self(value.into())
+    }
+
+    /// The key records about this identifier are stored under so that the identifier itself is never a key
+    pub fn storage_key(&self) -> blake3::Hash {
+        blake3::hash(self.0.as_bytes())
+    }
+}
+
 impl secrecy::DebugSecret for Lease {}
 impl secrecy::DebugSecret for Role {}
 impl secrecy::DebugSecret for TaiTimestamp {}
@@ -196,9 +320,14 @@ impl secrecy::CloneableSecret for Role {}
 impl secrecy::CloneableSecret for TaiTimestamp {}
 impl secrecy::CloneableSecret for Identifier {}
 
+/// The outcome of the garbage collector handling one entry
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum GcExec {
+    /// An expired or corrupted token was removed
     Hit,
+    /// The registry pointed at a token that no longer exists
     Miss,
+    /// An entry could not be decoded and was removed
     MalformedOperation,
 }
 
@@ -208,6 +337,18 @@ pub enum SgStatusCode {
     AuthorizedToken,
     Revoked,
     Rejected,
+    Expired,
+    AuthenticPassphrase,
+    AlreadyRegistered,
+    StateChanged,
+    TransitionDenied,
+    Locked,
+    Unlocked,
+    UnlockPending(usize),
+    RecoveryCodeUsed(usize),
+    Enrolled,
+    ConfigIntact,
+    ConfigTampered,
     AccessGranted,
     AccessDenied,
     BadDeserialize,
@@ -215,7 +356,7 @@ pub enum SgStatusCode {
     TuringDbOp(anyhow::Error),
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum Ping {
     Unreachable,
     Online,
@@ -223,16 +364,29 @@ pub enum Ping {
     //Varied, //TODO add ping ms
 }
 
-use turingdb::TuringEngine;
+use crate::storage::Storage;
 use async_trait::async_trait;
 #[async_trait]
 pub trait SecurityCheck {
-    async fn issue(self, db_engine: &TuringEngine) -> Result<secrecy::SecretString>;
+    async fn issue(self, storage: &dyn Storage) -> Result<secrecy::SecretString>;
+
+    async fn authorize(key: &str, storage: &dyn Storage) -> Result<SgStatusCode>;
+
+    async fn authenticate(key: &str, storage: &dyn Storage) -> Result<SgStatusCode>;
+
+    async fn revoke(key: &str, storage: &dyn Storage) -> Result<SgStatusCode>;
+}
+
+/// ### `SecurityCheck` for stateless tokens made and verified with server keys
+/// Every operation takes the `Keyring` holding the keys next to the `Storage`
+#[async_trait]
+pub trait KeyedSecurityCheck {
+    async fn issue(self, keyring: &crate::Keyring, storage: &dyn Storage) -> Result<secrecy::SecretString>;
 
-    async fn authorize(key: &str, db_engine: &TuringEngine) -> Result<SgStatusCode>;
+    async fn authorize(key: &str, keyring: &crate::Keyring, storage: &dyn Storage) -> Result<SgStatusCode>;
 
-    async fn authenticate(key: &str, db_engine: &TuringEngine) -> Result<SgStatusCode>;
+    async fn authenticate(key: &str, keyring: &crate::Keyring, storage: &dyn Storage) -> Result<SgStatusCode>;
 
-    async fn revoke(key: &str, db_engine: &TuringEngine) -> Result<SgStatusCode>;
+    async fn revoke(key: &str, keyring: &crate::Keyring, storage: &dyn Storage) -> Result<SgStatusCode>;
 }
 
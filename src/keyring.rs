@@ -0,0 +1,134 @@
+use secrecy::{SecretVec, ExposeSecret};
+use async_lock::RwLock;
+use anyhow::{Result, bail};
+use std::collections::HashMap;
+
+/// What a key in the `Keyring` is used for, each purpose has its own keys and active key
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum KeyPurpose {
+    /// `AeadToken` encryption keys
+    Aead,
+    /// `BrancaToken` keys
+    Branca,
+    /// `PasetoToken` v4.local keys, 32 bytes
+    PasetoLocal,
+    /// `PasetoToken` v4.public Ed25519 signing keys, the 32 byte seed followed by the 32 byte public key
+    PasetoSign,
+    /// `PasetoToken` v4.public Ed25519 public keys of partner services, 32 bytes
+    PasetoVerify,
+    /// `JwtToken` HS256 secrets, at least 32 bytes
+    JwtHmac,
+    /// `JwtToken` EdDSA signing keys as PKCS#8 DER
+    JwtSign,
+    /// `JwtToken` EdDSA public keys, 32 bytes, the public half of every `JwtSign` key must be installed here too
+    JwtVerify,
+    /// `Macaroon` root keys, 32 bytes
+    Macaroon,
+    /// Keys that enrolled `Otp` secrets are encrypted with in storage, 32 bytes
+    OtpSecret,
+    /// `SignedCookie` MAC keys, 32 bytes
+    Cookie,
+}
+
+#[derive(Default)]
+struct PurposeKeys {
+    keys: HashMap<String, SecretVec<u8>>,
+    active: Option<String>,
+}
+
+/// ### A store of server keys
+/// Stateless tokens carry the ID of the key they were made with, so keys can be rotated by
+/// installing and activating a new key while older keys stay available to verify existing tokens
+/// until they are retired. New tokens are always made with the active key of their `KeyPurpose`.
+/// A keyring is passed explicitly to everything that makes or verifies stateless tokens, like a `Storage`,
+/// so it is usually created once at startup and shared, for example in an `Arc`
+#[derive(Default)]
+pub struct Keyring {
+    purposes: RwLock<HashMap<KeyPurpose, PurposeKeys>>,
+}
+
+impl Keyring {
+    /// An empty keyring
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a key, replacing any key with the same ID.
+    /// Key IDs are part of token headers so they must be non-empty ASCII alphanumerics, `-` or `_`
+    pub async fn install(&self, purpose: KeyPurpose, kid: &str, key: SecretVec<u8>) -> Result<()> {
+        if kid.is_empty() || !kid.bytes().all(|byte| byte.is_ascii_alphanumeric() || byte == b'-' || byte == b'_') {
+            bail!("Invalid key ID `{}`", kid);
+        }
+
+        self.purposes.write().await
+            .entry(purpose)
+            .or_default()
+            .keys.insert(kid.into(), key);
+
+        Ok(())
+    }
+
+    /// Make an installed key the one new tokens are made with
+    pub async fn activate(&self, purpose: KeyPurpose, kid: &str) -> Result<()> {
+        let mut keyring = self.purposes.write().await;
+        let keys = keyring.entry(purpose).or_default();
+
+        if !keys.keys.contains_key(kid) {
+            bail!("No key with ID `{}` is installed for {:?}", kid, purpose);
+        }
+        keys.active = Some(kid.into());
+
+        Ok(())
+    }
+
+    /// Remove a key, tokens made with it can no longer be verified.
+    /// Returns `true` if the key existed
+    pub async fn retire(&self, purpose: KeyPurpose, kid: &str) -> bool {
+        let mut keyring = self.purposes.write().await;
+
+        match keyring.get_mut(&purpose) {
+            Some(keys) => {
+                if keys.active.as_deref() == Some(kid) {
+                    keys.active = None;
+                }
+
+                keys.keys.remove(kid).is_some()
+            },
+            None => false,
+        }
+    }
+
+    /// Run `operation` with the ID and bytes of the active key, `None` if there is no active key
+    pub async fn with_active<R>(&self, purpose: KeyPurpose, operation: impl FnOnce(&str, &[u8]) -> R) -> Option<R> {
+        let keyring = self.purposes.read().await;
+        let keys = keyring.get(&purpose)?;
+        let kid = keys.active.as_ref()?;
+        let key = keys.keys.get(kid)?;
+
+        Some(operation(kid, key.expose_secret()))
+    }
+
+    /// Run `operation` with each key of a purpose, the active key first, until it returns `Some`.
+    /// For token formats that do not carry a key ID
+    pub async fn find_map<R>(&self, purpose: KeyPurpose, mut operation: impl FnMut(&[u8]) -> Option<R>) -> Option<R> {
+        let keyring = self.purposes.read().await;
+        let keys = keyring.get(&purpose)?;
+
+        let active = keys.active.as_ref().and_then(|kid| keys.keys.get(kid));
+        let others = keys.keys.iter()
+            .filter(|(kid, _)| Some(*kid) != keys.active.as_ref())
+            .map(|(_, key)| key);
+
+        active.into_iter()
+            .chain(others)
+            .find_map(|key| operation(key.expose_secret()))
+    }
+
+    /// Run `operation` with the bytes of the key with ID `kid`, `None` if there is no such key
+    pub async fn with_key<R>(&self, purpose: KeyPurpose, kid: &str, operation: impl FnOnce(&[u8]) -> R) -> Option<R> {
+        let keyring = self.purposes.read().await;
+        let key = keyring.get(&purpose)?.keys.get(kid)?;
+
+        Some(operation(key.expose_secret()))
+    }
+}
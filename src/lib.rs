@@ -9,10 +9,22 @@
 mod tokens;
 mod global;
 mod storage;
+mod gc;
+mod config;
+mod auth;
+mod access;
+mod keyring;
+mod qr;
 
 pub use tokens::*;
 pub use global::*;
 pub use storage::*;
+pub use gc::*;
+pub use config::*;
+pub use auth::*;
+pub use access::*;
+pub use keyring::*;
+pub use qr::*;
 
 // Secrets engine handles Deny, Authenticate, Authorize, Reject, Revoke (DAARR) for all secrets
 // TODO Add jemalloc as the allocator
\ No newline at end of file
@@ -0,0 +1,64 @@
+use qrcode::{QrCode, EcLevel, render::{svg, unicode}};
+use image::{DynamicImage, ImageOutputFormat, Luma};
+use secrecy::{SecretString, SecretVec, ExposeSecret};
+use anyhow::{Result, anyhow};
+
+/// Side in pixels that SVG and PNG renderings are scaled up to at least
+pub const QR_MIN_DIMENSION: u32 = 256;
+
+/// ### A QR code of an `otpauth://` URI or an issued token
+/// Everything is rendered locally. The encoded data is usually a secret so every rendering is
+/// returned as a secret too, it should only be shown to the user it was made for.
+/// Codes use `EcLevel::M` and keep the quiet zone most scanners need
+#[derive(Debug, Clone)]
+pub struct QrImage {
+    code: QrCode,
+}
+
+impl QrImage {
+    /// Encode raw bytes
+    pub fn new(data: &[u8]) -> Result<Self> {
+        let code = QrCode::with_error_correction_level(data, EcLevel::M)
+            .map_err(|error| anyhow!("Unable to encode the QR code: {}", error))?;
+
+        Ok(Self { code })
+    }
+
+    /// Encode a secret such as an `otpauth://` URI or a token returned by `SecurityCheck::issue()` or `KeyedSecurityCheck::issue()`
+    pub fn from_secret(secret: &SecretString) -> Result<Self> {
+        QrImage::new(secret.expose_secret().as_bytes())
+    }
+
+    /// The number of modules on each side, without the quiet zone
+    pub fn width(&self) -> usize {
+        self.code.width()
+    }
+
+    /// An SVG document
+    pub fn to_svg(&self) -> SecretString {
+        SecretString::new(self.code.render::<svg::Color>()
+            .min_dimensions(QR_MIN_DIMENSION, QR_MIN_DIMENSION)
+            .build())
+    }
+
+    /// A grayscale PNG image
+    pub fn to_png(&self) -> Result<SecretVec<u8>> {
+        let image = self.code.render::<Luma<u8>>()
+            .min_dimensions(QR_MIN_DIMENSION, QR_MIN_DIMENSION)
+            .build();
+
+        let mut png = Vec::new();
+        DynamicImage::ImageLuma8(image).write_to(&mut png, ImageOutputFormat::Png)?;
+
+        Ok(SecretVec::new(png))
+    }
+
+    /// Unicode half blocks for a terminal, two modules per character so the code stays square.
+    /// The colors are inverted so the code scans on terminals with a dark background
+    pub fn to_unicode(&self) -> SecretString {
+        SecretString::new(self.code.render::<unicode::Dense1x2>()
+            .dark_color(unicode::Dense1x2::Light)
+            .light_color(unicode::Dense1x2::Dark)
+            .build())
+    }
+}
@@ -0,0 +1,31 @@
+mod turing;
+
+pub use turing::*;
+
+use anyhow::Result;
+use async_trait::async_trait;
+
+/// ### A pluggable key-value backend for secrets
+/// Every key lives inside a `namespace`, for example `TOKEN_SESSION_DOCUMENT` for sessions,
+/// so that different subsystems can share one backend without their keys colliding.
+/// Implementations must make `compare_and_swap` atomic with respect to the other operations
+/// on the same key since it is used to consume one-time leases.
+#[async_trait]
+pub trait Storage: Send + Sync {
+    /// Insert a value, overwriting any value already under `key`
+    async fn put(&self, namespace: &str, key: &[u8], value: &[u8]) -> Result<()>;
+
+    /// Get the value under `key` if it exists
+    async fn get(&self, namespace: &str, key: &[u8]) -> Result<Option<Vec<u8>>>;
+
+    /// Remove the value under `key` returning `true` if it existed
+    async fn delete(&self, namespace: &str, key: &[u8]) -> Result<bool>;
+
+    /// List all the key-value pairs in a namespace
+    async fn scan(&self, namespace: &str) -> Result<Vec<(Vec<u8>, Vec<u8>)>>;
+
+    /// Replace the value under `key` with `new` only if the current value is `current`.
+    /// `None` for `current` means the key must not exist and `None` for `new` removes the key.
+    /// Returns `true` if the swap happened
+    async fn compare_and_swap(&self, namespace: &str, key: &[u8], current: Option<&[u8]>, new: Option<&[u8]>) -> Result<bool>;
+}
@@ -0,0 +1,131 @@
+use crate::{global::TOKEN_DB_PATH, storage::Storage, SgConfig};
+
+use turingdb::TuringEngine;
+use custom_codes::DbOps;
+use async_lock::Mutex;
+use async_trait::async_trait;
+use anyhow::Result;
+use std::path::{Path, PathBuf};
+
+/// ### `Storage` backed by a TuringDB database
+/// Each namespace is a document inside the database at `db_path`.
+/// Writes are serialized through a lock so that `compare_and_swap` is atomic.
+pub struct TuringStorage {
+    engine: TuringEngine,
+    db_path: PathBuf,
+    guard: Mutex<()>,
+}
+
+impl TuringStorage {
+    /// Use the database at `db_path`
+    pub fn new(engine: TuringEngine, db_path: &Path) -> Self {
+        Self {
+            engine,
+            db_path: db_path.to_path_buf(),
+            guard: Mutex::new(()),
+        }
+    }
+
+    /// Use the database at `TOKEN_DB_PATH`
+    pub fn with_default_path(engine: TuringEngine) -> Self {
+        Self::new(engine, TOKEN_DB_PATH.as_ref())
+    }
+
+    /// Use the database at `storage.db_path` of the configuration
+    pub fn from_config(engine: TuringEngine, config: &SgConfig) -> Self {
+        Self::new(engine, &config.storage.db_path)
+    }
+
+    /// The underlying `TuringEngine`
+    pub fn engine(&self) -> &TuringEngine {
+        &self.engine
+    }
+
+    async fn field_get(&self, namespace: &str, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        match TuringEngine::field_get(&self.engine,
+            &self.db_path,
+            namespace.as_ref(),
+            key,
+        ).await? {
+            DbOps::FieldContents(data) => Ok(Some(data)),
+            _ => Ok(None),
+        }
+    }
+
+    async fn field_put(&self, namespace: &str, key: &[u8], value: &[u8]) -> Result<()> {
+        if self.field_get(namespace, key).await?.is_some() {
+            TuringEngine::field_remove(&self.engine, &self.db_path, namespace.as_ref(), key).await?;
+        }
+
+        TuringEngine::field_insert(&self.engine,
+            &self.db_path,
+            namespace.as_ref(),
+            key,
+            value,
+        ).await?;
+
+        Ok(())
+    }
+
+    async fn field_delete(&self, namespace: &str, key: &[u8]) -> Result<bool> {
+        match self.field_get(namespace, key).await? {
+            Some(_) => {
+                TuringEngine::field_remove(&self.engine, &self.db_path, namespace.as_ref(), key).await?;
+
+                Ok(true)
+            },
+            None => Ok(false),
+        }
+    }
+}
+
+#[async_trait]
+impl Storage for TuringStorage {
+    async fn put(&self, namespace: &str, key: &[u8], value: &[u8]) -> Result<()> {
+        let _guard = self.guard.lock().await;
+
+        self.field_put(namespace, key, value).await
+    }
+
+    async fn get(&self, namespace: &str, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.field_get(namespace, key).await
+    }
+
+    async fn delete(&self, namespace: &str, key: &[u8]) -> Result<bool> {
+        let _guard = self.guard.lock().await;
+
+        self.field_delete(namespace, key).await
+    }
+
+    async fn scan(&self, namespace: &str) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let fields = match TuringEngine::field_list(&self.engine, &self.db_path, namespace.as_ref()).await? {
+            DbOps::FieldList(fields) => fields,
+            _ => return Ok(Vec::new()),
+        };
+
+        let mut pairs = Vec::with_capacity(fields.len());
+        for field in fields {
+            let key: Vec<u8> = field.into();
+            if let Some(value) = self.field_get(namespace, &key).await? {
+                pairs.push((key, value));
+            }
+        }
+
+        Ok(pairs)
+    }
+
+    async fn compare_and_swap(&self, namespace: &str, key: &[u8], current: Option<&[u8]>, new: Option<&[u8]>) -> Result<bool> {
+        let _guard = self.guard.lock().await;
+
+        if self.field_get(namespace, key).await?.as_deref() != current {
+            return Ok(false);
+        }
+
+        match new {
+            Some(value) => self.field_put(namespace, key, value).await?,
+            None => { self.field_delete(namespace, key).await?; },
+        }
+
+        Ok(true)
+    }
+}
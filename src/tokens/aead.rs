@@ -0,0 +1,294 @@
+use crate::{global::{
+    Identifier,
+    Lease,
+    Role,
+    SgStatusCode,
+}, storage::Storage, Keyring, KeyPurpose, RoleHierarchy};
+
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce, aead::{Aead, NewAead, Payload}};
+use secrecy::{Secret, SecretString, ExposeSecret};
+use tai64::TAI64N;
+use anyhow::{Result, anyhow, bail};
+use serde::{Serialize, Deserialize};
+use std::collections::BTreeMap;
+
+/// Version prefix of the token header
+pub const AEAD_TOKEN_VERSION: &str = "v1";
+/// Length in bytes of the random ChaCha20-Poly1305 nonce
+pub const AEAD_NONCE_LEN: usize = 12;
+/// Length in bytes of a ChaCha20-Poly1305 key
+pub const AEAD_KEY_LEN: usize = 32;
+
+/// ### A stateless token whose payload is encrypted with ChaCha20-Poly1305
+/// The token is `v1.<key ID>.hex(nonce || ciphertext)` where the plaintext holds the identifier, role,
+/// lease, issue time and custom claims, so none of them are visible to the client.
+/// The header is authenticated as associated data so the key ID can not be swapped.
+/// Keys come from the `Keyring` under `KeyPurpose::Aead`, tokens are made with the active key
+/// and verified with the key named in their header so keys can be rotated.
+/// Nonces are random so a key should be rotated well before it has made 2^32 tokens.
+/// Nothing is stored, `authenticate` and `authorize` only decrypt and check the lease, and
+/// for the same reason only `Lease::Lifetime` and `Lease::DateExpiryTAI` can be used
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AeadToken {
+    identifier: Secret<Identifier>,
+    role: Secret<Role>,
+    lease: Secret<Lease>,
+    issued: TAI64N,
+    claims: BTreeMap<String, String>,
+}
+
+impl AeadToken {
+    pub fn new(identifier: Identifier, role: Role, lease: Lease) -> Self {
+        Self {
+            identifier: Secret::new(identifier),
+            role: Secret::new(role),
+            lease: Secret::new(lease),
+            issued: TAI64N::now(),
+            claims: BTreeMap::new(),
+        }
+    }
+
+    /// Add a custom claim
+    pub fn claim(mut self, name: &str, value: &str) -> Self {
+        self.claims.insert(name.into(), value.into());
+
+        self
+    }
+
+    pub fn get_identifier(&self) -> &Identifier {
+        self.identifier.expose_secret()
+    }
+
+    pub fn get_role(&self) -> &Role {
+        self.role.expose_secret()
+    }
+
+    pub fn get_lease(&self) -> &Lease {
+        self.lease.expose_secret()
+    }
+
+    pub fn get_issued(&self) -> TAI64N {
+        self.issued
+    }
+
+    pub fn get_claims(&self) -> &BTreeMap<String, String> {
+        &self.claims
+    }
+
+    /// Decrypt a token and check its lease.
+    /// Returns `SgStatusCode::AuthenticToken` with the decrypted token if it is valid,
+    /// `SgStatusCode::Expired` if its lease has passed and `SgStatusCode::Rejected` otherwise
+    pub async fn decrypt(token: &str, keyring: &Keyring) -> (SgStatusCode, Option<AeadToken>) {
+        let mut parts = token.splitn(3, '.');
+        let (version, kid, body) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(version), Some(kid), Some(body)) => (version, kid, body),
+            _ => return (SgStatusCode::Rejected, None),
+        };
+
+        if version != AEAD_TOKEN_VERSION {
+            return (SgStatusCode::Rejected, None);
+        }
+
+        let body = match hex::decode(body) {
+            Ok(body) if body.len() > AEAD_NONCE_LEN => body,
+            _ => return (SgStatusCode::Rejected, None),
+        };
+        let header = format!("{}.{}", version, kid);
+        let (nonce, ciphertext) = body.split_at(AEAD_NONCE_LEN);
+
+        let plaintext = keyring.with_key(KeyPurpose::Aead, kid, |key| {
+            if key.len() != AEAD_KEY_LEN {
+                return None;
+            }
+
+            ChaCha20Poly1305::new(Key::from_slice(key))
+                .decrypt(Nonce::from_slice(nonce), Payload { msg: ciphertext, aad: header.as_bytes() })
+                .ok()
+        }).await;
+
+        let decoded = match plaintext.flatten().map(|plaintext| bincode::deserialize::<AeadToken>(&plaintext)) {
+            Some(Ok(decoded)) => decoded,
+            Some(Err(_)) => return (SgStatusCode::BadDeserialize, None),
+            None => return (SgStatusCode::Rejected, None),
+        };
+
+        let status = decoded.get_lease().stateless_status();
+
+        match status {
+            SgStatusCode::AuthenticToken => (status, Some(decoded)),
+            status => (status, None),
+        }
+    }
+
+    /// Authorize a token only if its role has the permissions of `required_role` in `hierarchy`
+    pub async fn authorize_for(token: &str, required_role: &Role, hierarchy: &RoleHierarchy, keyring: &Keyring) -> SgStatusCode {
+        match AeadToken::decrypt(token, keyring).await {
+            (SgStatusCode::AuthenticToken, Some(decoded)) => match hierarchy.satisfies(decoded.get_role(), required_role) {
+                true => SgStatusCode::AccessGranted,
+                false => SgStatusCode::AccessDenied,
+            },
+            (SgStatusCode::Rejected, _) => SgStatusCode::AccessDenied,
+            (status, _) => status,
+        }
+    }
+}
+
+use async_trait::async_trait;
+#[async_trait]
+impl crate::global::KeyedSecurityCheck for AeadToken {
+    /// Encrypt the token with the active `KeyPurpose::Aead` key, nothing is stored
+    async fn issue(self, keyring: &Keyring, _storage: &dyn Storage) -> Result<SecretString> {
+        self.get_lease().ensure_stateless()?;
+
+        let plaintext = bincode::serialize::<Self>(&self)?;
+
+        let mut nonce = [0_u8; AEAD_NONCE_LEN];
+        getrandom::getrandom(&mut nonce).map_err(|error| anyhow!("{}", error))?;
+
+        let encrypted = keyring.with_active(KeyPurpose::Aead, |kid, key| {
+            if key.len() != AEAD_KEY_LEN {
+                bail!("The `{}` key must be {} bytes", kid, AEAD_KEY_LEN);
+            }
+
+            let header = format!("{}.{}", AEAD_TOKEN_VERSION, kid);
+            let ciphertext = ChaCha20Poly1305::new(Key::from_slice(key))
+                .encrypt(Nonce::from_slice(&nonce), Payload { msg: &plaintext, aad: header.as_bytes() })
+                .map_err(|_| anyhow!("Unable to encrypt the token"))?;
+
+            let mut body = nonce.to_vec();
+            body.extend_from_slice(&ciphertext);
+
+            Ok(format!("{}.{}", header, hex::encode(&body)))
+        }).await;
+
+        match encrypted {
+            Some(token) => Ok(SecretString::new(token?)),
+            None => bail!("There is no active {:?} key in the keyring", KeyPurpose::Aead),
+        }
+    }
+
+    async fn authenticate(key: &str, keyring: &Keyring, _storage: &dyn Storage) -> Result<SgStatusCode> {
+        let (status, _) = AeadToken::decrypt(key, keyring).await;
+
+        Ok(status)
+    }
+
+    async fn authorize(key: &str, keyring: &Keyring, _storage: &dyn Storage) -> Result<SgStatusCode> {
+        match AeadToken::decrypt(key, keyring).await {
+            (SgStatusCode::AuthenticToken, _) => Ok(SgStatusCode::AccessGranted),
+            (SgStatusCode::Rejected, _) => Ok(SgStatusCode::AccessDenied),
+            (status, _) => Ok(status),
+        }
+    }
+
+    /// Stateless tokens can not be revoked, they end with their lease or when their key is retired
+    async fn revoke(_key: &str, _keyring: &Keyring, _storage: &dyn Storage) -> Result<SgStatusCode> {
+        Ok(SgStatusCode::Rejected)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{KeyedSecurityCheck, MemPrngStore};
+    use futures_lite::future::block_on;
+    use secrecy::SecretVec;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    async fn install(keyring: &Keyring, kid: &str, byte: u8) {
+        keyring.install(KeyPurpose::Aead, kid, SecretVec::new(vec![byte; AEAD_KEY_LEN])).await.unwrap();
+        keyring.activate(KeyPurpose::Aead, kid).await.unwrap();
+    }
+
+    async fn issue(lease: Lease, keyring: &Keyring) -> SecretString {
+        AeadToken::new(Identifier::new("alice"), Role::User, lease)
+            .claim("tenant", "acme")
+            .issue(keyring, &MemPrngStore::new()).await
+            .unwrap()
+    }
+
+    #[test]
+    fn tokens_round_trip_and_reject_tampering() {
+        block_on(async {
+            let keyring = Keyring::new();
+            install(&keyring, "aead-1", 1).await;
+            let token = issue(Lease::Lifetime, &keyring).await;
+
+            match AeadToken::decrypt(token.expose_secret(), &keyring).await {
+                (SgStatusCode::AuthenticToken, Some(decoded)) => {
+                    assert_eq!(decoded.get_role(), &Role::User);
+                    assert_eq!(decoded.get_claims().get("tenant").map(String::as_str), Some("acme"));
+                },
+                (status, _) => panic!("{:?}", status),
+            }
+
+            let mut tampered = token.expose_secret().clone();
+            let flipped = if tampered.ends_with('0') { '1' } else { '0' };
+            tampered.pop();
+            tampered.push(flipped);
+            assert!(matches!(AeadToken::decrypt(&tampered, &keyring).await, (SgStatusCode::Rejected, None)));
+
+            // The header is associated data, naming another installed key does not decrypt
+            install(&keyring, "aead-2", 1).await;
+            let renamed = token.expose_secret().replacen("aead-1", "aead-2", 1);
+            assert!(matches!(AeadToken::decrypt(&renamed, &keyring).await, (SgStatusCode::Rejected, None)));
+
+            let other = Keyring::new();
+            install(&other, "aead-1", 2).await;
+            assert!(matches!(AeadToken::decrypt(token.expose_secret(), &other).await, (SgStatusCode::Rejected, None)));
+        });
+    }
+
+    #[test]
+    fn rotated_keys_decrypt_until_retired() {
+        block_on(async {
+            let keyring = Keyring::new();
+            install(&keyring, "aead-1", 1).await;
+            let old = issue(Lease::Lifetime, &keyring).await;
+
+            install(&keyring, "aead-2", 2).await;
+            let new = issue(Lease::Lifetime, &keyring).await;
+
+            assert!(matches!(AeadToken::decrypt(old.expose_secret(), &keyring).await, (SgStatusCode::AuthenticToken, Some(_))));
+            assert!(keyring.retire(KeyPurpose::Aead, "aead-1").await);
+            assert!(matches!(AeadToken::decrypt(old.expose_secret(), &keyring).await, (SgStatusCode::Rejected, None)));
+            assert!(matches!(AeadToken::decrypt(new.expose_secret(), &keyring).await, (SgStatusCode::AuthenticToken, Some(_))));
+        });
+    }
+
+    #[test]
+    fn tokens_expire_and_need_stateless_leases() {
+        block_on(async {
+            let keyring = Keyring::new();
+            install(&keyring, "aead-1", 1).await;
+
+            let expired = issue(Lease::DateExpiryTAI(TAI64N::from_system_time(&UNIX_EPOCH)), &keyring).await;
+            assert!(matches!(AeadToken::decrypt(expired.expose_secret(), &keyring).await, (SgStatusCode::Expired, None)));
+
+            let live = issue(Lease::DateExpiryTAI(TAI64N::now() + Duration::from_secs(60)), &keyring).await;
+            assert!(matches!(AeadToken::decrypt(live.expose_secret(), &keyring).await, (SgStatusCode::AuthenticToken, Some(_))));
+
+            let counted = AeadToken::new(Identifier::new("alice"), Role::User, Lease::OnDownloads(2));
+            assert!(counted.issue(&keyring, &MemPrngStore::new()).await.is_err());
+        });
+    }
+
+    #[test]
+    fn only_time_based_leases_are_stateless() {
+        assert!(Lease::Lifetime.ensure_stateless().is_ok());
+        assert!(Lease::DateExpiryTAI(TAI64N::now()).ensure_stateless().is_ok());
+
+        for lease in &[
+            Lease::FirstAccess,
+            Lease::OnDownload,
+            Lease::OnDownloads(3),
+            Lease::OnUpload,
+            Lease::OnUploads(3),
+            Lease::OnDisconnection,
+            Lease::Corrupted,
+        ] {
+            assert!(lease.ensure_stateless().is_err());
+            assert!(matches!(lease.stateless_status(), SgStatusCode::Rejected));
+        }
+    }
+}
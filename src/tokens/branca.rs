@@ -0,0 +1,232 @@
+use crate::{global::{
+    Identifier,
+    Lease,
+    Role,
+    SgStatusCode,
+}, storage::Storage, Keyring, KeyPurpose, RevocationList};
+
+use secrecy::{Secret, SecretString, ExposeSecret};
+use anyhow::{Result, anyhow, bail};
+use serde::{Serialize, Deserialize};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Length in bytes of a branca key
+pub const BRANCA_KEY_LEN: usize = 32;
+
+/// ### A branca token carrying the identifier, role and lease
+/// Branca only has a fixed TTL so the expiry comes from the TAI64N based `Lease` in the payload
+/// and the branca TTL is not used. Keys come from the `Keyring` under `KeyPurpose::Branca`,
+/// tokens are made with the active key and every installed key is tried when decoding.
+/// `BrancaToken::decode` is fully stateless while the `KeyedSecurityCheck` implementation also consults
+/// the `RevocationList` so that tokens can be revoked before their lease ends.
+/// Only `Lease::Lifetime` and `Lease::DateExpiryTAI` can be used
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BrancaToken {
+    identifier: Secret<Identifier>,
+    role: Secret<Role>,
+    lease: Secret<Lease>,
+}
+
+impl BrancaToken {
+    pub fn new(identifier: Identifier, role: Role, lease: Lease) -> Self {
+        Self {
+            identifier: Secret::new(identifier),
+            role: Secret::new(role),
+            lease: Secret::new(lease),
+        }
+    }
+
+    pub fn get_identifier(&self) -> &Identifier {
+        self.identifier.expose_secret()
+    }
+
+    pub fn get_role(&self) -> &Role {
+        self.role.expose_secret()
+    }
+
+    pub fn get_lease(&self) -> &Lease {
+        self.lease.expose_secret()
+    }
+
+    /// Decode a token and check its lease without consulting the `RevocationList`.
+    /// Returns `SgStatusCode::AuthenticToken` with the token if it is valid,
+    /// `SgStatusCode::Expired` if its lease has passed and `SgStatusCode::Rejected` otherwise
+    pub async fn decode(token: &str, keyring: &Keyring) -> (SgStatusCode, Option<BrancaToken>) {
+        let payload = keyring.find_map(KeyPurpose::Branca, |key| {
+            // A TTL of 0 disables the branca TTL check, the lease is checked below
+            branca::decode(token, key, 0).ok()
+        }).await;
+
+        let decoded = match payload.map(|payload| bincode::deserialize::<BrancaToken>(&payload)) {
+            Some(Ok(decoded)) => decoded,
+            Some(Err(_)) => return (SgStatusCode::BadDeserialize, None),
+            None => return (SgStatusCode::Rejected, None),
+        };
+
+        let status = decoded.get_lease().stateless_status();
+
+        match status {
+            SgStatusCode::AuthenticToken => (status, Some(decoded)),
+            status => (status, None),
+        }
+    }
+
+    /// Decode a token and check that it is not on the `RevocationList`
+    pub async fn verify(token: &str, keyring: &Keyring, storage: &dyn Storage) -> Result<(SgStatusCode, Option<BrancaToken>)> {
+        match BrancaToken::decode(token, keyring).await {
+            (SgStatusCode::AuthenticToken, Some(decoded)) => {
+                match RevocationList::is_revoked(token.as_bytes(), storage).await? {
+                    true => Ok((SgStatusCode::Revoked, None)),
+                    false => Ok((SgStatusCode::AuthenticToken, Some(decoded))),
+                }
+            },
+            outcome => Ok(outcome),
+        }
+    }
+}
+
+use async_trait::async_trait;
+#[async_trait]
+impl crate::global::KeyedSecurityCheck for BrancaToken {
+    /// Encode the token with the active `KeyPurpose::Branca` key, nothing is stored
+    async fn issue(self, keyring: &Keyring, _storage: &dyn Storage) -> Result<SecretString> {
+        self.get_lease().ensure_stateless()?;
+
+        let payload = bincode::serialize::<Self>(&self)?;
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as u32;
+
+        let encoded = keyring.with_active(KeyPurpose::Branca, |kid, key| {
+            if key.len() != BRANCA_KEY_LEN {
+                bail!("The `{}` key must be {} bytes", kid, BRANCA_KEY_LEN);
+            }
+
+            branca::encode(&payload, key, timestamp).map_err(|error| anyhow!("{:?}", error))
+        }).await;
+
+        match encoded {
+            Some(token) => Ok(SecretString::new(token?)),
+            None => bail!("There is no active {:?} key in the keyring", KeyPurpose::Branca),
+        }
+    }
+
+    async fn authenticate(key: &str, keyring: &Keyring, storage: &dyn Storage) -> Result<SgStatusCode> {
+        let (status, _) = BrancaToken::verify(key, keyring, storage).await?;
+
+        Ok(status)
+    }
+
+    async fn authorize(key: &str, keyring: &Keyring, storage: &dyn Storage) -> Result<SgStatusCode> {
+        match BrancaToken::verify(key, keyring, storage).await? {
+            (SgStatusCode::AuthenticToken, _) => Ok(SgStatusCode::AccessGranted),
+            (SgStatusCode::Rejected, _) | (SgStatusCode::Revoked, _) => Ok(SgStatusCode::AccessDenied),
+            (status, _) => Ok(status),
+        }
+    }
+
+    /// Put a valid token on the `RevocationList` until its lease ends
+    async fn revoke(key: &str, keyring: &Keyring, storage: &dyn Storage) -> Result<SgStatusCode> {
+        match BrancaToken::decode(key, keyring).await {
+            (SgStatusCode::AuthenticToken, Some(decoded)) => {
+                RevocationList::revoke(key.as_bytes(), decoded.get_lease(), storage).await
+            },
+            _ => Ok(SgStatusCode::Rejected),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{KeyedSecurityCheck, MemPrngStore};
+    use futures_lite::future::block_on;
+    use secrecy::SecretVec;
+    use tai64::TAI64N;
+    use std::time::Duration;
+
+    async fn install(keyring: &Keyring, kid: &str, byte: u8) {
+        keyring.install(KeyPurpose::Branca, kid, SecretVec::new(vec![byte; BRANCA_KEY_LEN])).await.unwrap();
+        keyring.activate(KeyPurpose::Branca, kid).await.unwrap();
+    }
+
+    async fn issue(lease: Lease, keyring: &Keyring) -> SecretString {
+        BrancaToken::new(Identifier::new("alice"), Role::User, lease)
+            .issue(keyring, &MemPrngStore::new()).await
+            .unwrap()
+    }
+
+    #[test]
+    fn tokens_round_trip_and_reject_tampering() {
+        block_on(async {
+            let keyring = Keyring::new();
+            install(&keyring, "branca-1", 1).await;
+            let token = issue(Lease::Lifetime, &keyring).await;
+
+            match BrancaToken::decode(token.expose_secret(), &keyring).await {
+                (SgStatusCode::AuthenticToken, Some(decoded)) => {
+                    assert_eq!(decoded.get_identifier().0, "alice");
+                    assert_eq!(decoded.get_role(), &Role::User);
+                },
+                (status, _) => panic!("{:?}", status),
+            }
+
+            let mut tampered = token.expose_secret().clone().into_bytes();
+            let middle = tampered.len() / 2;
+            tampered[middle] = if tampered[middle] == b'a' { b'b' } else { b'a' };
+            let tampered = String::from_utf8(tampered).unwrap();
+            assert!(matches!(BrancaToken::decode(&tampered, &keyring).await, (SgStatusCode::Rejected, None)));
+
+            let other = Keyring::new();
+            install(&other, "branca-1", 2).await;
+            assert!(matches!(BrancaToken::decode(token.expose_secret(), &other).await, (SgStatusCode::Rejected, None)));
+        });
+    }
+
+    #[test]
+    fn rotated_keys_decode_until_retired() {
+        block_on(async {
+            let keyring = Keyring::new();
+            install(&keyring, "branca-1", 1).await;
+            let old = issue(Lease::Lifetime, &keyring).await;
+
+            install(&keyring, "branca-2", 2).await;
+            let new = issue(Lease::Lifetime, &keyring).await;
+
+            assert!(matches!(BrancaToken::decode(old.expose_secret(), &keyring).await, (SgStatusCode::AuthenticToken, Some(_))));
+            assert!(keyring.retire(KeyPurpose::Branca, "branca-1").await);
+            assert!(matches!(BrancaToken::decode(old.expose_secret(), &keyring).await, (SgStatusCode::Rejected, None)));
+            assert!(matches!(BrancaToken::decode(new.expose_secret(), &keyring).await, (SgStatusCode::AuthenticToken, Some(_))));
+        });
+    }
+
+    #[test]
+    fn tokens_expire_and_need_stateless_leases() {
+        block_on(async {
+            let keyring = Keyring::new();
+            install(&keyring, "branca-1", 1).await;
+
+            let expired = issue(Lease::DateExpiryTAI(TAI64N::from_system_time(&UNIX_EPOCH)), &keyring).await;
+            assert!(matches!(BrancaToken::decode(expired.expose_secret(), &keyring).await, (SgStatusCode::Expired, None)));
+
+            let live = issue(Lease::DateExpiryTAI(TAI64N::now() + Duration::from_secs(60)), &keyring).await;
+            assert!(matches!(BrancaToken::decode(live.expose_secret(), &keyring).await, (SgStatusCode::AuthenticToken, Some(_))));
+
+            let counted = BrancaToken::new(Identifier::new("alice"), Role::User, Lease::OnDownload);
+            assert!(counted.issue(&keyring, &MemPrngStore::new()).await.is_err());
+        });
+    }
+
+    #[test]
+    fn revoked_tokens_fail_verification() {
+        block_on(async {
+            let keyring = Keyring::new();
+            let storage = MemPrngStore::new();
+            install(&keyring, "branca-1", 1).await;
+            let token = issue(Lease::DateExpiryTAI(TAI64N::now() + Duration::from_secs(60)), &keyring).await;
+
+            assert!(matches!(BrancaToken::authorize(token.expose_secret(), &keyring, &storage).await.unwrap(), SgStatusCode::AccessGranted));
+            BrancaToken::revoke(token.expose_secret(), &keyring, &storage).await.unwrap();
+            assert!(matches!(BrancaToken::verify(token.expose_secret(), &keyring, &storage).await.unwrap(), (SgStatusCode::Revoked, None)));
+            assert!(matches!(BrancaToken::authorize(token.expose_secret(), &keyring, &storage).await.unwrap(), SgStatusCode::AccessDenied));
+        });
+    }
+}
@@ -0,0 +1,206 @@
+use crate::{global::{
+    Identifier,
+    Lease,
+    Role,
+    SgStatusCode,
+}, Keyring, KeyPurpose};
+
+use secrecy::{Secret, SecretString, ExposeSecret};
+use tai64::TAI64N;
+use anyhow::{Result, bail};
+use serde::{Serialize, Deserialize};
+use std::convert::TryInto;
+
+/// Domain separation for the MAC so a cookie tag can never be confused with another keyed hash
+const COOKIE_CONTEXT: &[u8] = b"SchemeGuardian signed cookie v1";
+
+/// ### A stateless cookie signed with `blake3` in keyed mode
+/// The cookie is `<key ID>.hex(payload).hex(tag)` where the payload holds the identifier, role, lease and issue time
+/// and the tag is the keyed `blake3` hash of the key ID and payload. The payload is signed but not encrypted.
+/// Keys come from the `Keyring` under `KeyPurpose::Cookie`, cookies are made with the active key
+/// and verified with the key named in the cookie so keys can be rotated.
+/// Only `Lease::Lifetime` and `Lease::DateExpiryTAI` can be used since nothing is stored to count usage
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SignedCookie {
+    identifier: Secret<Identifier>,
+    role: Secret<Role>,
+    lease: Secret<Lease>,
+    issued: TAI64N,
+}
+
+impl SignedCookie {
+    pub fn new(identifier: Identifier, role: Role, lease: Lease) -> Self {
+        Self {
+            identifier: Secret::new(identifier),
+            role: Secret::new(role),
+            lease: Secret::new(lease),
+            issued: TAI64N::now(),
+        }
+    }
+
+    /// MAC the cookie with the active `KeyPurpose::Cookie` key
+    pub async fn encode(&self, keyring: &Keyring) -> Result<SecretString> {
+        self.lease.expose_secret().ensure_stateless()?;
+
+        let payload = bincode::serialize::<Self>(self)?;
+
+        let signed = keyring.with_active(KeyPurpose::Cookie, |kid, key| {
+            let tag = match SignedCookie::tag(key, kid, &payload) {
+                Some(tag) => tag,
+                None => bail!("The `{}` key must be {} bytes", kid, blake3::KEY_LEN),
+            };
+
+            Ok(format!("{}.{}.{}", kid, hex::encode(&payload), tag.to_hex()))
+        }).await;
+
+        match signed {
+            Some(cookie) => Ok(SecretString::new(cookie?)),
+            None => bail!("There is no active {:?} key in the keyring", KeyPurpose::Cookie),
+        }
+    }
+
+    /// Check the tag of a cookie with the key named in it, and its lease.
+    /// Returns `SgStatusCode::AuthenticToken` with the decoded cookie if it is valid,
+    /// `SgStatusCode::Expired` if its lease has passed and `SgStatusCode::Rejected` otherwise
+    pub async fn verify(cookie: &str, keyring: &Keyring) -> (SgStatusCode, Option<SignedCookie>) {
+        let mut parts = cookie.splitn(3, '.');
+        let (kid, payload, tag) = match (parts.next(), parts.next().map(hex::decode), parts.next().map(hex::decode)) {
+            (Some(kid), Some(Ok(payload)), Some(Ok(tag))) => (kid, payload, tag),
+            _ => return (SgStatusCode::Rejected, None),
+        };
+
+        let tag: [u8; blake3::OUT_LEN] = match tag[..].try_into() {
+            Ok(tag) => tag,
+            Err(_) => return (SgStatusCode::Rejected, None),
+        };
+
+        // `blake3::Hash` compares in constant time
+        let authentic = keyring.with_key(KeyPurpose::Cookie, kid, |key| SignedCookie::tag(key, kid, &payload)).await
+            .flatten()
+            .map_or(false, |expected| expected == blake3::Hash::from(tag));
+        if !authentic {
+            return (SgStatusCode::Rejected, None);
+        }
+
+        let decoded = match bincode::deserialize::<SignedCookie>(&payload) {
+            Ok(decoded) => decoded,
+            Err(_) => return (SgStatusCode::BadDeserialize, None),
+        };
+
+        let status = decoded.get_lease().stateless_status();
+
+        match status {
+            SgStatusCode::AuthenticToken => (status, Some(decoded)),
+            status => (status, None),
+        }
+    }
+
+    pub fn get_identifier(&self) -> &Identifier {
+        self.identifier.expose_secret()
+    }
+
+    pub fn get_role(&self) -> &Role {
+        self.role.expose_secret()
+    }
+
+    pub fn get_lease(&self) -> &Lease {
+        self.lease.expose_secret()
+    }
+
+    pub fn get_issued(&self) -> TAI64N {
+        self.issued
+    }
+
+    /// The keyed hash of the key ID and payload, `None` if the key is not `blake3::KEY_LEN` bytes
+    fn tag(key: &[u8], kid: &str, payload: &[u8]) -> Option<blake3::Hash> {
+        let key: &[u8; blake3::KEY_LEN] = key.try_into().ok()?;
+
+        let mut hasher = blake3::Hasher::new_keyed(key);
+        hasher.update(COOKIE_CONTEXT);
+        hasher.update(kid.as_bytes());
+        hasher.update(b".");
+        hasher.update(payload);
+
+        Some(hasher.finalize())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_lite::future::block_on;
+    use secrecy::SecretVec;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    async fn install(keyring: &Keyring, kid: &str, byte: u8) {
+        keyring.install(KeyPurpose::Cookie, kid, SecretVec::new(vec![byte; blake3::KEY_LEN])).await.unwrap();
+        keyring.activate(KeyPurpose::Cookie, kid).await.unwrap();
+    }
+
+    fn cookie(lease: Lease) -> SignedCookie {
+        SignedCookie::new(Identifier::new("alice"), Role::User, lease)
+    }
+
+    #[test]
+    fn cookies_round_trip_and_reject_tampering() {
+        block_on(async {
+            let keyring = Keyring::new();
+            install(&keyring, "cookie-1", 1).await;
+
+            let encoded = cookie(Lease::Lifetime).encode(&keyring).await.unwrap();
+            match SignedCookie::verify(encoded.expose_secret(), &keyring).await {
+                (SgStatusCode::AuthenticToken, Some(decoded)) => assert_eq!(decoded.get_role(), &Role::User),
+                (status, _) => panic!("{:?}", status),
+            }
+
+            let mut parts = encoded.expose_secret().splitn(3, '.').map(String::from).collect::<Vec<String>>();
+            let flipped = if parts[1].ends_with('0') { '1' } else { '0' };
+            parts[1].pop();
+            parts[1].push(flipped);
+            assert!(matches!(SignedCookie::verify(&parts.join("."), &keyring).await, (SgStatusCode::Rejected, None)));
+
+            // The key ID is part of the tag, naming another installed key does not verify
+            install(&keyring, "cookie-2", 1).await;
+            let renamed = encoded.expose_secret().replacen("cookie-1", "cookie-2", 1);
+            assert!(matches!(SignedCookie::verify(&renamed, &keyring).await, (SgStatusCode::Rejected, None)));
+
+            let other = Keyring::new();
+            install(&other, "cookie-1", 2).await;
+            assert!(matches!(SignedCookie::verify(encoded.expose_secret(), &other).await, (SgStatusCode::Rejected, None)));
+        });
+    }
+
+    #[test]
+    fn rotated_keys_verify_until_retired() {
+        block_on(async {
+            let keyring = Keyring::new();
+            install(&keyring, "cookie-1", 1).await;
+            let old = cookie(Lease::Lifetime).encode(&keyring).await.unwrap();
+
+            install(&keyring, "cookie-2", 2).await;
+            let new = cookie(Lease::Lifetime).encode(&keyring).await.unwrap();
+            assert!(new.expose_secret().starts_with("cookie-2."));
+
+            assert!(matches!(SignedCookie::verify(old.expose_secret(), &keyring).await, (SgStatusCode::AuthenticToken, Some(_))));
+            assert!(keyring.retire(KeyPurpose::Cookie, "cookie-1").await);
+            assert!(matches!(SignedCookie::verify(old.expose_secret(), &keyring).await, (SgStatusCode::Rejected, None)));
+            assert!(matches!(SignedCookie::verify(new.expose_secret(), &keyring).await, (SgStatusCode::AuthenticToken, Some(_))));
+        });
+    }
+
+    #[test]
+    fn cookies_expire_and_need_stateless_leases() {
+        block_on(async {
+            let keyring = Keyring::new();
+            install(&keyring, "cookie-1", 1).await;
+
+            let expired = cookie(Lease::DateExpiryTAI(TAI64N::from_system_time(&UNIX_EPOCH))).encode(&keyring).await.unwrap();
+            assert!(matches!(SignedCookie::verify(expired.expose_secret(), &keyring).await, (SgStatusCode::Expired, None)));
+
+            let live = cookie(Lease::DateExpiryTAI(TAI64N::now() + Duration::from_secs(60))).encode(&keyring).await.unwrap();
+            assert!(matches!(SignedCookie::verify(live.expose_secret(), &keyring).await, (SgStatusCode::AuthenticToken, Some(_))));
+
+            assert!(cookie(Lease::OnDownload).encode(&keyring).await.is_err());
+        });
+    }
+}
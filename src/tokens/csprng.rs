@@ -0,0 +1,202 @@
+use crate::{global::{
+    SgStatusCode,
+    Usage,
+    Role,
+}, storage::Storage, PrngToken, ExpiryAction, RoleHierarchy, AccessLevel, Scheme};
+
+use secrecy::SecretString;
+use zeroize::Zeroize;
+use anyhow::Result;
+
+/// The length in bytes of the random secret handed to the token bearer
+pub const CSPRNG_SECRET_LEN: usize = 32;
+
+/// ### A token whose secret is 256 bits of OS randomness
+/// Only the `blake3` hash of the secret is stored, the record under that hash is the
+/// same `PrngToken` metadata so leases and roles behave the same for both token types.
+/// The raw secret is returned to the caller once from `issue()` and never stored.
+#[derive(Debug, Default)]
+pub struct CsprngToken {
+    metadata: PrngToken,
+}
+
+impl CsprngToken {
+    /// Create a new token from the metadata that will be stored with it
+    pub fn new(metadata: PrngToken) -> Self {
+        Self { metadata }
+    }
+
+    /// Decode a hex secret and hash it into its storage key.
+    /// Returns `None` if the secret is not valid hex of the right length
+    pub fn to_storage_key(key: &str) -> Option<blake3::Hash> {
+        let mut secret = hex::decode(key).ok()?;
+
+        let outcome = if secret.len() == CSPRNG_SECRET_LEN {
+            Some(blake3::hash(&secret))
+        }else {
+            None
+        };
+        secret.zeroize();
+
+        outcome
+    }
+
+    /// Authorize the token with hex `key` only if its role has the permissions of `required_role` in `hierarchy`
+    pub async fn authorize_for(key: &str, required_role: &Role, hierarchy: &RoleHierarchy, storage: &dyn Storage) -> Result<SgStatusCode> {
+        let hashed_token = match CsprngToken::to_storage_key(key) {
+            Some(hash) => hash,
+            None => return Ok(SgStatusCode::AccessDenied),
+        };
+
+        PrngToken::authorize_role_hash(&hashed_token, required_role, hierarchy, storage).await
+    }
+
+    /// Authorize the token with hex `key` only if one of its grants allows `permission` over `resource`
+    pub async fn authorize_resource(key: &str, resource: &str, permission: AccessLevel, storage: &dyn Storage) -> Result<SgStatusCode> {
+        let hashed_token = match CsprngToken::to_storage_key(key) {
+            Some(hash) => hash,
+            None => return Ok(SgStatusCode::AccessDenied),
+        };
+
+        PrngToken::authorize_resource_hash(&hashed_token, resource, permission, storage).await
+    }
+
+    /// Authorize the token with hex `key` for the `requested` scheme
+    pub async fn authorize_scheme(key: &str, requested: &Scheme, storage: &dyn Storage) -> Result<SgStatusCode> {
+        let hashed_token = match CsprngToken::to_storage_key(key) {
+            Some(hash) => hash,
+            None => return Ok(SgStatusCode::AccessDenied),
+        };
+
+        PrngToken::authorize_scheme_hash(&hashed_token, requested, storage).await
+    }
+
+    /// Authorize a `Usage` of the token with hex `key`, consuming usage-counted leases.
+    /// A token bound to a `Scheme` is denied
+    pub async fn authorize_usage(key: &str, usage: Usage, storage: &dyn Storage) -> Result<SgStatusCode> {
+        let hashed_token = match CsprngToken::to_storage_key(key) {
+            Some(hash) => hash,
+            None => return Ok(SgStatusCode::AccessDenied),
+        };
+
+        PrngToken::consume(&hashed_token, usage, None, storage, ExpiryAction::default()).await
+    }
+}
+
+use async_trait::async_trait;
+#[async_trait]
+impl crate::global::SecurityCheck for CsprngToken {
+    /// Create a token
+    async fn issue(self, storage: &dyn Storage) -> Result<SecretString> {
+        let mut secret = [0_u8; CSPRNG_SECRET_LEN];
+        getrandom::getrandom(&mut secret).map_err(|error| anyhow::anyhow!("{}", error))?;
+
+        let hashed_token = blake3::hash(&secret);
+
+        self.metadata.store(&hashed_token, storage).await?;
+
+        let token = SecretString::new(hex::encode(&secret));
+        secret.zeroize();
+
+        Ok(token)
+    }
+
+    async fn authenticate(key: &str, storage: &dyn Storage) -> Result<SgStatusCode> {
+        let hashed_token = match CsprngToken::to_storage_key(key) {
+            Some(hash) => hash,
+            None => return Ok(SgStatusCode::Rejected),
+        };
+
+        PrngToken::authenticate_hash(&hashed_token, storage, ExpiryAction::default()).await
+    }
+
+    async fn authorize(key: &str, storage: &dyn Storage) -> Result<SgStatusCode> {
+        let hashed_token = match CsprngToken::to_storage_key(key) {
+            Some(hash) => hash,
+            None => return Ok(SgStatusCode::AccessDenied),
+        };
+
+        PrngToken::authorize_hash(&hashed_token, storage, ExpiryAction::default()).await
+    }
+
+    async fn revoke(key: &str, storage: &dyn Storage) -> Result<SgStatusCode> {
+        let hashed_token = match CsprngToken::to_storage_key(key) {
+            Some(hash) => hash,
+            None => return Ok(SgStatusCode::Rejected),
+        };
+
+        match PrngToken::remove(&hashed_token, storage).await? {
+            true => Ok(SgStatusCode::Revoked),
+            false => Ok(SgStatusCode::Rejected)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{global::{Identifier, SecurityCheck, GC_REGISTRY, TOKEN_SESSION_DOCUMENT}, MemPrngStore};
+    use futures_lite::future::block_on;
+    use secrecy::ExposeSecret;
+
+    #[test]
+    fn tokens_round_trip_until_revoked() {
+        block_on(async {
+            let storage = MemPrngStore::new();
+            let token = CsprngToken::new(PrngToken::default().identifier(Identifier::new("alice")).role(Role::User))
+                .issue(&storage).await
+                .unwrap();
+            let key = token.expose_secret();
+
+            assert!(matches!(CsprngToken::authenticate(key, &storage).await.unwrap(), SgStatusCode::AuthenticToken));
+            assert!(matches!(CsprngToken::authorize(key, &storage).await.unwrap(), SgStatusCode::AccessGranted));
+            assert!(matches!(CsprngToken::authorize_for(key, &Role::User, &RoleHierarchy::default(), &storage).await.unwrap(), SgStatusCode::AccessGranted));
+
+            assert!(matches!(CsprngToken::revoke(key, &storage).await.unwrap(), SgStatusCode::Revoked));
+            assert!(storage.is_empty(TOKEN_SESSION_DOCUMENT).await);
+            assert!(storage.is_empty(GC_REGISTRY).await);
+
+            assert!(matches!(CsprngToken::authenticate(key, &storage).await.unwrap(), SgStatusCode::Rejected));
+            assert!(matches!(CsprngToken::revoke(key, &storage).await.unwrap(), SgStatusCode::Rejected));
+        });
+    }
+
+    #[test]
+    fn only_the_hash_of_the_secret_is_stored() {
+        block_on(async {
+            let storage = MemPrngStore::new();
+            let token = CsprngToken::default().issue(&storage).await.unwrap();
+            let secret = hex::decode(token.expose_secret()).unwrap();
+            assert_eq!(secret.len(), CSPRNG_SECRET_LEN);
+
+            let stored = storage.scan(TOKEN_SESSION_DOCUMENT).await.unwrap();
+            assert_eq!(stored.len(), 1);
+
+            let (key, record) = &stored[0];
+            assert_eq!(key.as_slice(), blake3::hash(&secret).as_bytes());
+            assert_eq!(CsprngToken::to_storage_key(token.expose_secret()), Some(blake3::hash(&secret)));
+            assert!(!record.windows(CSPRNG_SECRET_LEN).any(|window| window == secret.as_slice()));
+        });
+    }
+
+    #[test]
+    fn wrong_secrets_are_rejected() {
+        block_on(async {
+            let storage = MemPrngStore::new();
+            let token = CsprngToken::default().issue(&storage).await.unwrap();
+            let hashed_token = CsprngToken::to_storage_key(token.expose_secret()).unwrap();
+
+            let mut other = [0_u8; CSPRNG_SECRET_LEN];
+            getrandom::getrandom(&mut other).unwrap();
+
+            // Knowing the storage key is not enough to use the token
+            for key in &[hex::encode(other), hex::encode(hashed_token.as_bytes()), "00ff".into(), "not hex".into()] {
+                assert!(matches!(CsprngToken::authenticate(key, &storage).await.unwrap(), SgStatusCode::Rejected));
+                assert!(matches!(CsprngToken::authorize(key, &storage).await.unwrap(), SgStatusCode::AccessDenied));
+                assert!(matches!(CsprngToken::revoke(key, &storage).await.unwrap(), SgStatusCode::Rejected));
+            }
+
+            assert!(matches!(CsprngToken::authenticate(token.expose_secret(), &storage).await.unwrap(), SgStatusCode::AuthenticToken));
+        });
+    }
+}
@@ -0,0 +1,210 @@
+use crate::{global::{
+    SgStatusCode,
+    Ping,
+    Lease,
+    TOKEN_SESSION_DOCUMENT,
+}, storage::Storage, PrngToken, ExpiryAction};
+
+use async_lock::RwLock;
+use async_io::Timer;
+use tai64::TAI64N;
+use anyhow::Result;
+use std::{collections::{HashMap, HashSet}, sync::Arc, time::Duration};
+
+/// An identifier the caller gives to a client connection
+pub type ConnectionId = u64;
+
+#[derive(Debug)]
+struct Connection {
+    hashed_token: blake3::Hash,
+    last_ping: TAI64N,
+}
+
+/// ### Ties tokens to live client connections
+/// A connection registers the token it authenticated with and then pings periodically.
+/// Each ping marks the token `Ping::Online` and updates its `last_active` time.
+/// A connection that disconnects or misses its heartbeat deadline is marked `Ping::Offline`,
+/// which revokes the token if it was issued with `Lease::OnDisconnection`.
+/// A `Lease::OnDisconnection` token that no connection holds, because it was never registered or
+/// was registered with a monitor that has since restarted, is revoked once it has been inactive
+/// for longer than the deadline
+pub struct HeartbeatMonitor {
+    storage: Arc<dyn Storage>,
+    deadline: Duration,
+    connections: RwLock<HashMap<ConnectionId, Connection>>,
+}
+
+impl HeartbeatMonitor {
+    /// A connection that has not pinged within `deadline` is considered disconnected
+    pub fn new(storage: Arc<dyn Storage>, deadline: Duration) -> Self {
+        Self {
+            storage,
+            deadline,
+            connections: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Bind the token stored under `hashed_token` to a connection and mark it online.
+    /// Only a valid `Lease::OnDisconnection` token can be registered, any other is `SgStatusCode::Rejected`
+    /// and an expired one is removed and reported as `SgStatusCode::Expired`
+    pub async fn register(&self, connection: ConnectionId, hashed_token: blake3::Hash) -> Result<SgStatusCode> {
+        match PrngToken::fetch(&hashed_token, self.storage.as_ref(), ExpiryAction::Revoke).await? {
+            (SgStatusCode::AuthenticToken, Some(token)) => {
+                if token.get_lease() != &Lease::OnDisconnection {
+                    return Ok(SgStatusCode::Rejected);
+                }
+            },
+            (status, _) => return Ok(status),
+        }
+
+        let status = PrngToken::heartbeat(&hashed_token, Ping::Online, self.storage.as_ref()).await?;
+
+        if let SgStatusCode::AuthenticToken = status {
+            self.connections.write().await.insert(connection, Connection {
+                hashed_token,
+                last_ping: TAI64N::now(),
+            });
+        }
+
+        Ok(status)
+    }
+
+    /// Record a ping from a registered connection
+    pub async fn ping(&self, connection: ConnectionId) -> Result<SgStatusCode> {
+        let hashed_token = match self.connections.write().await.get_mut(&connection) {
+            Some(registered) => {
+                registered.last_ping = TAI64N::now();
+
+                registered.hashed_token
+            },
+            None => return Ok(SgStatusCode::Rejected),
+        };
+
+        let status = PrngToken::heartbeat(&hashed_token, Ping::Online, self.storage.as_ref()).await?;
+
+        if let SgStatusCode::AuthenticToken = status {
+            Ok(status)
+        }else {
+            self.connections.write().await.remove(&connection);
+
+            Ok(status)
+        }
+    }
+
+    /// Mark a connection as closed
+    pub async fn disconnect(&self, connection: ConnectionId) -> Result<SgStatusCode> {
+        match self.connections.write().await.remove(&connection) {
+            Some(registered) => PrngToken::heartbeat(&registered.hashed_token, Ping::Offline, self.storage.as_ref()).await,
+            None => Ok(SgStatusCode::Rejected),
+        }
+    }
+
+    /// Disconnect every connection that has missed its heartbeat deadline and revoke every
+    /// `Lease::OnDisconnection` token that no connection holds and that has been inactive past the deadline.
+    /// Returns the number of connections dropped and orphaned tokens revoked
+    pub async fn reap(&self) -> Result<usize> {
+        let now = TAI64N::now();
+
+        let stale = {
+            let mut connections = self.connections.write().await;
+            let stale_ids = connections.iter()
+                .filter(|(_, registered)| registered.last_ping + self.deadline <= now)
+                .map(|(id, _)| *id)
+                .collect::<Vec<ConnectionId>>();
+
+            stale_ids.into_iter()
+                .filter_map(|id| connections.remove(&id))
+                .collect::<Vec<Connection>>()
+        };
+
+        for registered in &stale {
+            PrngToken::heartbeat(&registered.hashed_token, Ping::Offline, self.storage.as_ref()).await?;
+        }
+
+        let orphaned = self.revoke_orphans(now).await?;
+
+        Ok(stale.len() + orphaned)
+    }
+
+    /// Scan the session document for `Lease::OnDisconnection` tokens without a live connection.
+    /// The removal is a compare-and-swap on the scanned record, so a token that is registered
+    /// or pinged concurrently has a new `last_active` and is kept
+    async fn revoke_orphans(&self, now: TAI64N) -> Result<usize> {
+        let live = self.connections.read().await
+            .values()
+            .map(|registered| registered.hashed_token.as_bytes().to_vec())
+            .collect::<HashSet<Vec<u8>>>();
+
+        let mut revoked = 0_usize;
+
+        for (key, data) in self.storage.scan(TOKEN_SESSION_DOCUMENT).await? {
+            if live.contains(&key) {
+                continue;
+            }
+
+            // Undecodable records are left to the garbage collector
+            let token = match bincode::deserialize::<PrngToken>(&data) {
+                Ok(token) => token,
+                Err(_) => continue,
+            };
+
+            if token.get_lease() != &Lease::OnDisconnection || token.get_last_active() + self.deadline > now {
+                continue;
+            }
+
+            if self.storage.compare_and_swap(TOKEN_SESSION_DOCUMENT, &key, Some(&data), None).await? {
+                revoked += 1;
+            }
+        }
+
+        Ok(revoked)
+    }
+
+    /// Reap missed heartbeats every `interval`, this never returns unless the storage fails
+    pub async fn run(&self, interval: Duration) -> Result<()> {
+        loop {
+            Timer::after(interval).await;
+            self.reap().await?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MemPrngStore;
+    use futures_lite::future::block_on;
+
+    #[test]
+    fn register_only_binds_on_disconnection_tokens() {
+        let storage = Arc::new(MemPrngStore::new());
+        let monitor = HeartbeatMonitor::new(storage.clone(), Duration::from_secs(60));
+
+        let token = PrngToken::default();
+        let hashed_token = token.token_hash();
+        block_on(token.store(&hashed_token, storage.as_ref())).unwrap();
+
+        let status = block_on(monitor.register(1, hashed_token)).unwrap();
+        assert!(matches!(status, SgStatusCode::Rejected));
+
+        let token = PrngToken::default().lease(Lease::OnDisconnection);
+        let hashed_token = token.token_hash();
+        block_on(token.store(&hashed_token, storage.as_ref())).unwrap();
+
+        let status = block_on(monitor.register(2, hashed_token)).unwrap();
+        assert!(matches!(status, SgStatusCode::AuthenticToken));
+    }
+
+    #[test]
+    fn reap_revokes_tokens_without_a_connection() {
+        let storage = Arc::new(MemPrngStore::new());
+        let monitor = HeartbeatMonitor::new(storage.clone(), Duration::from_secs(0));
+
+        let token = PrngToken::default().lease(Lease::OnDisconnection);
+        let hashed_token = token.token_hash();
+        block_on(token.store(&hashed_token, storage.as_ref())).unwrap();
+
+        assert_eq!(block_on(monitor.reap()).unwrap(), 1);
+        assert!(block_on(storage.is_empty(TOKEN_SESSION_DOCUMENT)));
+    }
+}
@@ -0,0 +1,528 @@
+use crate::{global::{
+    Identifier,
+    Lease,
+    Role,
+    SgStatusCode,
+}, storage::Storage, Keyring, KeyPurpose, RevocationList, RoleHierarchy};
+
+use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey, Header, Validation, errors::ErrorKind};
+use secrecy::{Secret, SecretString, ExposeSecret};
+use tai64::TAI64N;
+use anyhow::{Result, anyhow, bail};
+use serde::{Serialize, Deserialize};
+use std::{collections::HashSet, time::{Duration, SystemTime, UNIX_EPOCH}};
+
+/// Minimum length in bytes of an HS256 secret
+pub const JWT_HMAC_KEY_LEN: usize = 32;
+/// Length in bytes of a random `jti`
+pub const JWT_ID_LEN: usize = 16;
+/// Default clock skew tolerance in seconds
+pub const JWT_DEFAULT_LEEWAY: u64 = 60;
+
+// TAI64 labels after the Unix epoch end at 2^63, which is 2^62 seconds after it
+const JWT_MAX_UNIX: u64 = 1 << 62;
+
+/// The JWS algorithms a `JwtToken` can be signed with.
+/// Every algorithm has its own `KeyPurpose` so a key can only ever be used with its algorithm
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JwtAlgorithm {
+    /// HMAC-SHA256 with a `KeyPurpose::JwtHmac` secret
+    HS256,
+    /// Ed25519 with a `KeyPurpose::JwtSign` key, verified with `KeyPurpose::JwtVerify`
+    EdDSA,
+}
+
+impl JwtAlgorithm {
+    fn to_algorithm(self) -> Algorithm {
+        match self {
+            JwtAlgorithm::HS256 => Algorithm::HS256,
+            JwtAlgorithm::EdDSA => Algorithm::EdDSA,
+        }
+    }
+}
+
+/// What is checked besides the signature, `exp` and `nbf`
+#[derive(Debug, Clone)]
+pub struct JwtValidation {
+    issuer: Option<String>,
+    audience: Option<String>,
+    leeway: u64,
+    allow_lifetime: bool,
+}
+
+impl Default for JwtValidation {
+    fn default() -> Self {
+        Self {
+            issuer: None,
+            audience: None,
+            leeway: JWT_DEFAULT_LEEWAY,
+            allow_lifetime: false,
+        }
+    }
+}
+
+impl JwtValidation {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Require the `iss` claim to be `issuer`
+    pub fn issuer(mut self, issuer: &str) -> Self {
+        self.issuer = Some(issuer.into());
+
+        self
+    }
+    /// Require the `aud` claim to contain `audience`
+    pub fn audience(mut self, audience: &str) -> Self {
+        self.audience = Some(audience.into());
+
+        self
+    }
+    /// Replace the clock skew tolerated on `exp` and `nbf`, defaults to `JWT_DEFAULT_LEEWAY` seconds
+    pub fn leeway(mut self, leeway: Duration) -> Self {
+        self.leeway = leeway.as_secs();
+
+        self
+    }
+    /// Accept tokens without an `exp` claim as `Lease::Lifetime` tokens, by default they are rejected
+    pub fn allow_lifetime(mut self) -> Self {
+        self.allow_lifetime = true;
+
+        self
+    }
+
+    fn to_validation(&self, algorithm: Algorithm) -> Validation {
+        // Only `algorithm` is accepted
+        let mut validation = Validation::new(algorithm);
+        validation.leeway = self.leeway;
+        validation.validate_nbf = true;
+        // `exp` is only optional when `Lease::Lifetime` tokens are allowed, it is still checked when present
+        validation.required_spec_claims = match self.allow_lifetime {
+            true => HashSet::new(),
+            false => ["exp".to_owned()].iter().cloned().collect(),
+        };
+
+        if let Some(issuer) = &self.issuer {
+            validation.set_issuer(&[issuer]);
+        }
+        if let Some(audience) = &self.audience {
+            validation.set_audience(&[audience]);
+        }
+
+        validation
+    }
+}
+
+/// `aud` is either a single audience or an array of them
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+enum JwtAudience {
+    One(String),
+    Many(Vec<String>),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct JwtClaims {
+    sub: String,
+    roles: Vec<String>,
+    iat: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    exp: Option<u64>,
+    jti: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    iss: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    aud: Option<JwtAudience>,
+}
+
+/// ### A JWT signed with HS256 or EdDSA
+/// The `Identifier` is the `sub` claim, the `Role` is the only entry of the `roles` claim, the issue time is `iat`
+/// and a `Lease::DateExpiryTAI` is `exp`, a `Lease::Lifetime` token has no `exp` and is only accepted by a
+/// `JwtValidation` that allows it. Every token gets a random `jti`.
+/// The `kid` header names the key in the `Keyring`, tokens without it are tried against every key of the algorithm.
+/// The algorithm comes from the header only to pick the `KeyPurpose`, so `alg: none`, unknown algorithms and
+/// tokens claiming HS256 with the ID of an EdDSA public key are all rejected.
+/// Revocation puts the `jti` on the `RevocationList` until the token expires.
+/// Only `Lease::Lifetime` and `Lease::DateExpiryTAI` can be used
+#[derive(Debug)]
+pub struct JwtToken {
+    algorithm: JwtAlgorithm,
+    identifier: Secret<Identifier>,
+    role: Secret<Role>,
+    lease: Secret<Lease>,
+    issued: TAI64N,
+    jti: String,
+    issuer: Option<String>,
+    audience: Vec<String>,
+}
+
+impl JwtToken {
+    pub fn new(algorithm: JwtAlgorithm, identifier: Identifier, role: Role, lease: Lease) -> Self {
+        Self {
+            algorithm,
+            identifier: Secret::new(identifier),
+            role: Secret::new(role),
+            lease: Secret::new(lease),
+            issued: TAI64N::now(),
+            jti: String::new(),
+            issuer: None,
+            audience: Vec::new(),
+        }
+    }
+    /// Set the `iss` claim
+    pub fn issuer(mut self, issuer: &str) -> Self {
+        self.issuer = Some(issuer.into());
+
+        self
+    }
+    /// Add an audience to the `aud` claim, it is a string with one audience and an array with more
+    pub fn audience(mut self, audience: &str) -> Self {
+        self.audience.push(audience.into());
+
+        self
+    }
+
+    pub fn get_algorithm(&self) -> JwtAlgorithm {
+        self.algorithm
+    }
+
+    pub fn get_identifier(&self) -> &Identifier {
+        self.identifier.expose_secret()
+    }
+
+    pub fn get_role(&self) -> &Role {
+        self.role.expose_secret()
+    }
+
+    pub fn get_lease(&self) -> &Lease {
+        self.lease.expose_secret()
+    }
+
+    pub fn get_issued(&self) -> TAI64N {
+        self.issued
+    }
+
+    /// The `jti` of a decoded token, empty before the token is issued
+    pub fn get_jti(&self) -> &str {
+        &self.jti
+    }
+
+    pub fn get_issuer(&self) -> Option<&str> {
+        self.issuer.as_deref()
+    }
+
+    pub fn get_audience(&self) -> &[String] {
+        &self.audience
+    }
+
+    /// Verify the signature and claims of a token without consulting the `RevocationList`.
+    /// Returns `SgStatusCode::AuthenticToken` with the token if it is valid,
+    /// `SgStatusCode::Expired` if its `exp` has passed even with the leeway,
+    /// `SgStatusCode::BadDeserialize` if the claims are malformed and `SgStatusCode::Rejected` otherwise
+    pub async fn decode(token: &str, validation: &JwtValidation, keyring: &Keyring) -> (SgStatusCode, Option<JwtToken>) {
+        let header = match jsonwebtoken::decode_header(token) {
+            Ok(header) => header,
+            Err(_) => return (SgStatusCode::Rejected, None),
+        };
+
+        let (algorithm, purpose) = match header.alg {
+            Algorithm::HS256 => (JwtAlgorithm::HS256, KeyPurpose::JwtHmac),
+            Algorithm::EdDSA => (JwtAlgorithm::EdDSA, KeyPurpose::JwtVerify),
+            _ => return (SgStatusCode::Rejected, None),
+        };
+        let rules = validation.to_validation(header.alg);
+
+        // A wrong key gives `None` so the next key is tried, any other error is final
+        let open = |key: &[u8]| {
+            let key = match algorithm {
+                JwtAlgorithm::HS256 => DecodingKey::from_secret(key),
+                JwtAlgorithm::EdDSA => DecodingKey::from_ed_der(key),
+            };
+
+            match jsonwebtoken::decode::<JwtClaims>(token, &key, &rules) {
+                Ok(data) => Some(Ok(data.claims)),
+                Err(error) => match error.kind() {
+                    ErrorKind::InvalidSignature => None,
+                    _ => Some(Err(error)),
+                },
+            }
+        };
+
+        let outcome = match header.kid {
+            Some(kid) => keyring.with_key(purpose, &kid, open).await.flatten(),
+            None => keyring.find_map(purpose, open).await,
+        };
+
+        let claims = match outcome {
+            Some(Ok(claims)) => claims,
+            Some(Err(error)) => return match error.kind() {
+                ErrorKind::ExpiredSignature => (SgStatusCode::Expired, None),
+                ErrorKind::Json(_) => (SgStatusCode::BadDeserialize, None),
+                _ => (SgStatusCode::Rejected, None),
+            },
+            None => return (SgStatusCode::Rejected, None),
+        };
+
+        match JwtToken::from_claims(algorithm, claims) {
+            Some(decoded) => (SgStatusCode::AuthenticToken, Some(decoded)),
+            None => (SgStatusCode::Rejected, None),
+        }
+    }
+
+    /// Decode a token and check that its `jti` is not on the `RevocationList`
+    pub async fn verify(token: &str, validation: &JwtValidation, keyring: &Keyring, storage: &dyn Storage) -> Result<(SgStatusCode, Option<JwtToken>)> {
+        match JwtToken::decode(token, validation, keyring).await {
+            (SgStatusCode::AuthenticToken, Some(decoded)) => {
+                match RevocationList::is_revoked(decoded.get_jti().as_bytes(), storage).await? {
+                    true => Ok((SgStatusCode::Revoked, None)),
+                    false => Ok((SgStatusCode::AuthenticToken, Some(decoded))),
+                }
+            },
+            outcome => Ok(outcome),
+        }
+    }
+
+    /// Authorize a token only if its role has the permissions of `required_role` in `hierarchy`
+    pub async fn authorize_for(token: &str, validation: &JwtValidation, required_role: &Role, hierarchy: &RoleHierarchy, keyring: &Keyring, storage: &dyn Storage) -> Result<SgStatusCode> {
+        match JwtToken::verify(token, validation, keyring, storage).await? {
+            (SgStatusCode::AuthenticToken, Some(decoded)) => match hierarchy.satisfies(decoded.get_role(), required_role) {
+                true => Ok(SgStatusCode::AccessGranted),
+                false => Ok(SgStatusCode::AccessDenied),
+            },
+            (SgStatusCode::Rejected, _) | (SgStatusCode::Revoked, _) => Ok(SgStatusCode::AccessDenied),
+            (status, _) => Ok(status),
+        }
+    }
+
+    /// Revoke a token that passes `validation` by putting its `jti` on the `RevocationList`
+    pub async fn revoke_with(token: &str, validation: &JwtValidation, keyring: &Keyring, storage: &dyn Storage) -> Result<SgStatusCode> {
+        match JwtToken::decode(token, validation, keyring).await {
+            (SgStatusCode::AuthenticToken, Some(decoded)) => {
+                RevocationList::revoke(decoded.get_jti().as_bytes(), decoded.get_lease(), storage).await
+            },
+            _ => Ok(SgStatusCode::Rejected),
+        }
+    }
+
+    fn to_claims(&self) -> Result<JwtClaims> {
+        self.get_lease().ensure_stateless()?;
+
+        let exp = match self.get_lease() {
+            Lease::DateExpiryTAI(expiry) => Some(JwtToken::to_unix(*expiry)?),
+            _ => None,
+        };
+
+        let mut jti = [0_u8; JWT_ID_LEN];
+        getrandom::getrandom(&mut jti).map_err(|error| anyhow!("{}", error))?;
+
+        Ok(JwtClaims {
+            sub: self.get_identifier().0.clone(),
+            roles: vec![self.get_role().name().into()],
+            iat: JwtToken::to_unix(self.issued)?,
+            exp,
+            jti: hex::encode(&jti),
+            iss: self.issuer.clone(),
+            aud: match self.audience.as_slice() {
+                [] => None,
+                [audience] => Some(JwtAudience::One(audience.clone())),
+                audience => Some(JwtAudience::Many(audience.to_vec())),
+            },
+        })
+    }
+
+    fn from_claims(algorithm: JwtAlgorithm, claims: JwtClaims) -> Option<Self> {
+        if claims.jti.is_empty() {
+            return None;
+        }
+
+        let role = match claims.roles.as_slice() {
+            [role] => Role::from_name(role),
+            _ => return None,
+        };
+        let lease = match claims.exp {
+            Some(exp) => Lease::DateExpiryTAI(JwtToken::from_unix(exp)?),
+            None => Lease::Lifetime,
+        };
+
+        let mut decoded = JwtToken::new(algorithm, Identifier::new(&claims.sub), role, lease);
+        decoded.issued = JwtToken::from_unix(claims.iat)?;
+        decoded.jti = claims.jti;
+        decoded.issuer = claims.iss;
+        decoded.audience = match claims.aud {
+            Some(JwtAudience::One(audience)) => vec![audience],
+            Some(JwtAudience::Many(audience)) => audience,
+            None => Vec::new(),
+        };
+
+        Some(decoded)
+    }
+
+    fn to_unix(time: TAI64N) -> Result<u64> {
+        let time = time.to_system_time().map_err(|error| anyhow!("{:?}", error))?;
+
+        Ok(time.duration_since(UNIX_EPOCH)?.as_secs())
+    }
+
+    /// `None` for times a `TAI64N` label or the platform `SystemTime` can not hold
+    fn from_unix(seconds: u64) -> Option<TAI64N> {
+        if seconds >= JWT_MAX_UNIX {
+            return None;
+        }
+
+        UNIX_EPOCH.checked_add(Duration::from_secs(seconds))
+            .map(|time| TAI64N::from_system_time(&time))
+    }
+}
+
+use async_trait::async_trait;
+#[async_trait]
+impl crate::global::KeyedSecurityCheck for JwtToken {
+    /// Sign the token with the active key of its algorithm, nothing is stored
+    async fn issue(self, keyring: &Keyring, _storage: &dyn Storage) -> Result<SecretString> {
+        let claims = self.to_claims()?;
+        let purpose = match self.algorithm {
+            JwtAlgorithm::HS256 => KeyPurpose::JwtHmac,
+            JwtAlgorithm::EdDSA => KeyPurpose::JwtSign,
+        };
+
+        let signed = keyring.with_active(purpose, |kid, key| {
+            let key = match self.algorithm {
+                JwtAlgorithm::HS256 => {
+                    if key.len() < JWT_HMAC_KEY_LEN {
+                        bail!("The `{}` key must be at least {} bytes", kid, JWT_HMAC_KEY_LEN);
+                    }
+
+                    EncodingKey::from_secret(key)
+                },
+                JwtAlgorithm::EdDSA => EncodingKey::from_ed_der(key),
+            };
+
+            let mut header = Header::new(self.algorithm.to_algorithm());
+            header.kid = Some(kid.into());
+
+            jsonwebtoken::encode(&header, &claims, &key).map_err(|error| anyhow!("Unable to sign the token: {}", error))
+        }).await;
+
+        match signed {
+            Some(token) => Ok(SecretString::new(token?)),
+            None => bail!("There is no active {:?} key in the keyring", purpose),
+        }
+    }
+
+    async fn authenticate(key: &str, keyring: &Keyring, storage: &dyn Storage) -> Result<SgStatusCode> {
+        let (status, _) = JwtToken::verify(key, &JwtValidation::default(), keyring, storage).await?;
+
+        Ok(status)
+    }
+
+    async fn authorize(key: &str, keyring: &Keyring, storage: &dyn Storage) -> Result<SgStatusCode> {
+        match JwtToken::verify(key, &JwtValidation::default(), keyring, storage).await? {
+            (SgStatusCode::AuthenticToken, _) => Ok(SgStatusCode::AccessGranted),
+            (SgStatusCode::Rejected, _) | (SgStatusCode::Revoked, _) => Ok(SgStatusCode::AccessDenied),
+            (status, _) => Ok(status),
+        }
+    }
+
+    /// Put the `jti` of a valid token on the `RevocationList` until its `exp`
+    async fn revoke(key: &str, keyring: &Keyring, storage: &dyn Storage) -> Result<SgStatusCode> {
+        JwtToken::revoke_with(key, &JwtValidation::default(), keyring, storage).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{KeyedSecurityCheck, MemPrngStore};
+    use futures_lite::future::block_on;
+    use secrecy::SecretVec;
+
+    async fn keyring() -> Keyring {
+        let keyring = Keyring::new();
+        keyring.install(KeyPurpose::JwtHmac, "hmac-1", SecretVec::new(vec![7_u8; JWT_HMAC_KEY_LEN])).await.unwrap();
+        keyring.activate(KeyPurpose::JwtHmac, "hmac-1").await.unwrap();
+
+        keyring
+    }
+
+    #[test]
+    fn lifetime_tokens_need_to_be_allowed() {
+        block_on(async {
+            let keyring = keyring().await;
+            let storage = MemPrngStore::new();
+
+            let token = JwtToken::new(JwtAlgorithm::HS256, Identifier::new("alice"), Role::User, Lease::Lifetime);
+            let token = token.issue(&keyring, &storage).await.unwrap();
+
+            assert!(matches!(JwtToken::decode(token.expose_secret(), &JwtValidation::default(), &keyring).await, (SgStatusCode::Rejected, None)));
+            assert!(matches!(
+                JwtToken::decode(token.expose_secret(), &JwtValidation::new().allow_lifetime(), &keyring).await,
+                (SgStatusCode::AuthenticToken, Some(_))
+            ));
+        });
+    }
+
+    #[test]
+    fn audience_is_a_string_or_an_array() {
+        block_on(async {
+            let keyring = keyring().await;
+            let storage = MemPrngStore::new();
+            let lease = Lease::DateExpiryTAI(TAI64N::now() + Duration::from_secs(60));
+
+            let token = JwtToken::new(JwtAlgorithm::HS256, Identifier::new("alice"), Role::User, lease)
+                .audience("billing")
+                .audience("reports");
+            let token = token.issue(&keyring, &storage).await.unwrap();
+
+            match JwtToken::decode(token.expose_secret(), &JwtValidation::new().audience("reports"), &keyring).await {
+                (SgStatusCode::AuthenticToken, Some(decoded)) => assert_eq!(decoded.get_audience().to_vec(), vec!["billing", "reports"]),
+                (status, _) => panic!("{:?}", status),
+            }
+            assert!(matches!(
+                JwtToken::decode(token.expose_secret(), &JwtValidation::new().audience("admin"), &keyring).await,
+                (SgStatusCode::Rejected, None)
+            ));
+        });
+
+        let one = serde_json::from_str::<JwtClaims>(r#"{"sub":"a","roles":["User"],"iat":0,"jti":"1","aud":"billing"}"#).unwrap();
+        assert!(matches!(one.aud, Some(JwtAudience::One(_))));
+        let many = serde_json::from_str::<JwtClaims>(r#"{"sub":"a","roles":["User"],"iat":0,"jti":"1","aud":["billing","reports"]}"#).unwrap();
+        assert!(matches!(many.aud, Some(JwtAudience::Many(ref audience)) if audience.len() == 2));
+    }
+
+    #[test]
+    fn revoked_tokens_fail_verification() {
+        block_on(async {
+            let keyring = keyring().await;
+            let storage = MemPrngStore::new();
+            let lease = Lease::DateExpiryTAI(TAI64N::now() + Duration::from_secs(60));
+
+            let token = JwtToken::new(JwtAlgorithm::HS256, Identifier::new("alice"), Role::User, lease)
+                .issue(&keyring, &storage).await.unwrap();
+            assert!(matches!(JwtToken::authenticate(token.expose_secret(), &keyring, &storage).await.unwrap(), SgStatusCode::AuthenticToken));
+
+            assert!(matches!(JwtToken::revoke(token.expose_secret(), &keyring, &storage).await.unwrap(), SgStatusCode::Revoked));
+            assert!(matches!(
+                JwtToken::verify(token.expose_secret(), &JwtValidation::default(), &keyring, &storage).await.unwrap(),
+                (SgStatusCode::Revoked, None)
+            ));
+            assert!(matches!(JwtToken::authorize(token.expose_secret(), &keyring, &storage).await.unwrap(), SgStatusCode::AccessDenied));
+        });
+    }
+
+    #[test]
+    fn out_of_range_times_are_rejected() {
+        assert!(JwtToken::from_unix(0).is_some());
+        assert!(JwtToken::from_unix(JWT_MAX_UNIX).is_none());
+        assert!(JwtToken::from_unix(u64::MAX).is_none());
+
+        let claims = JwtClaims {
+            sub: "alice".into(),
+            roles: vec!["User".into()],
+            iat: u64::MAX,
+            exp: Some(u64::MAX),
+            jti: "1".into(),
+            iss: None,
+            aud: None,
+        };
+        assert!(JwtToken::from_claims(JwtAlgorithm::HS256, claims).is_none());
+    }
+}
@@ -0,0 +1,364 @@
+use crate::{global::{
+    Identifier,
+    Lease,
+    Role,
+    SgStatusCode,
+}, storage::Storage, Keyring, KeyPurpose, RevocationList, RoleHierarchy, AccessLevel, Grant, Scheme};
+
+use secrecy::{Secret, SecretString, ExposeSecret};
+use anyhow::{Result, anyhow, bail};
+use serde::{Serialize, Deserialize};
+use std::convert::TryFrom;
+
+/// Version prefix of an encoded macaroon
+pub const MACAROON_VERSION: &str = "mc1";
+/// Length in bytes of the random nonce that identifies a macaroon and all its attenuations
+pub const MACAROON_NONCE_LEN: usize = 16;
+
+const MACAROON_CONTEXT: &[u8] = b"SchemeGuardian macaroon v1";
+
+/// ### A first-party caveat, a condition every request made with the macaroon must meet
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Caveat {
+    /// The macaroon is only valid within the lease, only `Lease::Lifetime` and `Lease::DateExpiryTAI` can be met
+    Lease(Lease),
+    /// The requested role must be one this role has the permissions of
+    RoleCeiling(Role),
+    /// The requested scheme must be covered by this scheme, see `Scheme::covers()`
+    Scheme(Scheme),
+    /// The requested resource and permission must be allowed by this grant
+    Resource(Grant),
+}
+
+/// ### What a request made with a macaroon asks for
+/// A `Caveat::Scheme` or `Caveat::Resource` is not met by a context that does not name a scheme or resource
+#[derive(Debug, Clone)]
+pub struct MacaroonContext {
+    role: Role,
+    scheme: Option<Scheme>,
+    resource: Option<(String, AccessLevel)>,
+}
+
+impl MacaroonContext {
+    /// A request that needs the permissions of `role`
+    pub fn new(role: Role) -> Self {
+        Self {
+            role,
+            scheme: None,
+            resource: None,
+        }
+    }
+    /// The scheme the request is made over
+    pub fn scheme(mut self, scheme: Scheme) -> Self {
+        self.scheme = Some(scheme);
+
+        self
+    }
+    /// The resource the request accesses and how
+    pub fn resource(mut self, resource: &str, permission: AccessLevel) -> Self {
+        self.resource = Some((resource.into(), permission));
+
+        self
+    }
+}
+
+/// ### A macaroon-style bearer token that holders can attenuate
+/// The signature is a chain of `blake3` keyed hashes, the first keyed with the root key from the
+/// `Keyring` under `KeyPurpose::Macaroon` over the key ID, nonce, identifier, role and the number of caveats
+/// it was issued with, and every next one keyed with the previous signature over one `Caveat`.
+/// Anyone holding a macaroon can add caveats with `attenuate()` without the root key, but removing a caveat
+/// would need the signature before it. The identifier and caveats are readable by the holder, only the signature is secret.
+/// Revoking a macaroon revokes every macaroon attenuated from the same issued macaroon, until the lease
+/// it was issued with ends whichever copy is revoked
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Macaroon {
+    kid: String,
+    nonce: [u8; MACAROON_NONCE_LEN],
+    identifier: Secret<Identifier>,
+    role: Secret<Role>,
+    caveats: Vec<Caveat>,
+    issued_caveats: usize,
+    signature: [u8; blake3::OUT_LEN],
+}
+
+impl Macaroon {
+    /// A macaroon for `identifier` whose role is the ceiling of every request made with it
+    pub fn new(identifier: Identifier, role: Role) -> Self {
+        Self {
+            kid: String::new(),
+            nonce: [0_u8; MACAROON_NONCE_LEN],
+            identifier: Secret::new(identifier),
+            role: Secret::new(role),
+            caveats: Vec::new(),
+            issued_caveats: 0,
+            signature: [0_u8; blake3::OUT_LEN],
+        }
+    }
+
+    /// Add a caveat and extend the signature chain, this does not need the root key
+    pub fn attenuate(mut self, caveat: Caveat) -> Result<Self> {
+        self.signature = Macaroon::extend(&self.signature, &caveat)?;
+        self.caveats.push(caveat);
+
+        Ok(self)
+    }
+
+    pub fn get_identifier(&self) -> &Identifier {
+        self.identifier.expose_secret()
+    }
+
+    pub fn get_role(&self) -> &Role {
+        self.role.expose_secret()
+    }
+
+    pub fn get_caveats(&self) -> &[Caveat] {
+        &self.caveats
+    }
+
+    /// Parse an encoded macaroon without verifying it, so a holder can attenuate it
+    pub fn parse(token: &str) -> Result<Macaroon> {
+        let mut parts = token.splitn(2, '.');
+        let body = match (parts.next(), parts.next()) {
+            (Some(MACAROON_VERSION), Some(body)) => body,
+            _ => bail!("Not a `{}` macaroon", MACAROON_VERSION),
+        };
+
+        Ok(bincode::deserialize::<Macaroon>(&hex::decode(body)?)?)
+    }
+
+    /// Encode the macaroon as `mc1.hex(bincode)`, for example after attenuating it
+    pub fn encode(&self) -> Result<SecretString> {
+        let body = bincode::serialize::<Self>(self)?;
+
+        Ok(SecretString::new(format!("{}.{}", MACAROON_VERSION, hex::encode(&body))))
+    }
+
+    /// Parse a macaroon and check its signature chain and `Caveat::Lease` caveats.
+    /// Returns `SgStatusCode::AuthenticToken` with the macaroon if it is valid,
+    /// `SgStatusCode::Expired` if a lease caveat has passed and `SgStatusCode::Rejected` otherwise
+    pub async fn decode(token: &str, keyring: &Keyring) -> (SgStatusCode, Option<Macaroon>) {
+        let macaroon = match Macaroon::parse(token) {
+            Ok(macaroon) => macaroon,
+            Err(_) => return (SgStatusCode::Rejected, None),
+        };
+
+        let expected = keyring.with_key(KeyPurpose::Macaroon, &macaroon.kid, |key| macaroon.sign(key).ok()).await.flatten();
+
+        // `blake3::Hash` compares in constant time
+        let authentic = match expected {
+            Some(expected) => blake3::Hash::from(expected) == blake3::Hash::from(macaroon.signature),
+            None => false,
+        };
+        if !authentic {
+            return (SgStatusCode::Rejected, None);
+        }
+
+        let mut status = SgStatusCode::AuthenticToken;
+        for caveat in &macaroon.caveats {
+            match caveat {
+                Caveat::Lease(lease @ Lease::Lifetime) | Caveat::Lease(lease @ Lease::DateExpiryTAI(_)) => {
+                    if lease.is_expired() {
+                        status = SgStatusCode::Expired;
+                    }
+                },
+                Caveat::Lease(_) => return (SgStatusCode::Rejected, None),
+                _ => (),
+            }
+        }
+
+        match status {
+            SgStatusCode::AuthenticToken => (status, Some(macaroon)),
+            status => (status, None),
+        }
+    }
+
+    /// Decode a macaroon, check it is not on the `RevocationList` and evaluate every caveat against `context`.
+    /// Returns `SgStatusCode::AccessGranted` if the role and every caveat allow the request,
+    /// `SgStatusCode::Expired` if a lease caveat has passed and `SgStatusCode::AccessDenied` otherwise
+    pub async fn verify(token: &str, context: &MacaroonContext, hierarchy: &RoleHierarchy, keyring: &Keyring, storage: &dyn Storage) -> Result<SgStatusCode> {
+        let macaroon = match Macaroon::decode(token, keyring).await {
+            (SgStatusCode::AuthenticToken, Some(macaroon)) => macaroon,
+            (SgStatusCode::Rejected, _) => return Ok(SgStatusCode::AccessDenied),
+            (status, _) => return Ok(status),
+        };
+
+        if RevocationList::is_revoked(&macaroon.nonce, storage).await? {
+            return Ok(SgStatusCode::AccessDenied);
+        }
+
+        let allowed = hierarchy.satisfies(macaroon.get_role(), &context.role)
+            && macaroon.caveats.iter().all(|caveat| Macaroon::holds(caveat, context, hierarchy));
+
+        match allowed {
+            true => Ok(SgStatusCode::AccessGranted),
+            false => Ok(SgStatusCode::AccessDenied),
+        }
+    }
+
+    fn holds(caveat: &Caveat, context: &MacaroonContext, hierarchy: &RoleHierarchy) -> bool {
+        match caveat {
+            // Checked by `decode()`
+            Caveat::Lease(_) => true,
+            Caveat::RoleCeiling(ceiling) => hierarchy.satisfies(ceiling, &context.role),
+            Caveat::Scheme(scheme) => match &context.scheme {
+                Some(requested) => scheme.covers(requested),
+                None => false,
+            },
+            Caveat::Resource(grant) => match &context.resource {
+                Some((resource, permission)) => grant.allows(resource, *permission),
+                None => false,
+            },
+        }
+    }
+
+    /// The full signature chain from the root key
+    fn sign(&self, root_key: &[u8]) -> Result<[u8; blake3::OUT_LEN]> {
+        let root_key = <&[u8; blake3::KEY_LEN]>::try_from(root_key)
+            .map_err(|_| anyhow!("A macaroon root key must be {} bytes", blake3::KEY_LEN))?;
+
+        let identity = bincode::serialize(&(&self.kid, &self.nonce, &self.identifier, &self.role, &self.issued_caveats))?;
+
+        let mut hasher = blake3::Hasher::new_keyed(root_key);
+        hasher.update(MACAROON_CONTEXT);
+        hasher.update(&identity);
+
+        let mut signature = *hasher.finalize().as_bytes();
+        for caveat in &self.caveats {
+            signature = Macaroon::extend(&signature, caveat)?;
+        }
+
+        Ok(signature)
+    }
+
+    fn extend(signature: &[u8; blake3::OUT_LEN], caveat: &Caveat) -> Result<[u8; blake3::OUT_LEN]> {
+        let caveat = bincode::serialize::<Caveat>(caveat)?;
+
+        Ok(*blake3::keyed_hash(signature, &caveat).as_bytes())
+    }
+
+    /// The earliest `Caveat::Lease` the macaroon was issued with, `Lease::Lifetime` if there is none.
+    /// Attenuated copies share the nonce, so a revocation must last as long as the longest lived copy
+    fn root_lease(&self) -> Lease {
+        self.caveats.iter()
+            .take(self.issued_caveats)
+            .filter_map(|caveat| match caveat {
+                Caveat::Lease(Lease::DateExpiryTAI(expiry)) => Some(*expiry),
+                _ => None,
+            })
+            .min()
+            .map_or(Lease::Lifetime, Lease::DateExpiryTAI)
+    }
+}
+
+use async_trait::async_trait;
+#[async_trait]
+impl crate::global::KeyedSecurityCheck for Macaroon {
+    /// Sign the macaroon and its caveats with the active `KeyPurpose::Macaroon` root key, nothing is stored
+    async fn issue(mut self, keyring: &Keyring, _storage: &dyn Storage) -> Result<SecretString> {
+        getrandom::getrandom(&mut self.nonce).map_err(|error| anyhow!("{}", error))?;
+        self.issued_caveats = self.caveats.len();
+
+        let signed = keyring.with_active(KeyPurpose::Macaroon, |kid, key| {
+            self.kid = kid.into();
+            self.signature = self.sign(key)?;
+
+            self.encode()
+        }).await;
+
+        match signed {
+            Some(token) => token,
+            None => bail!("There is no active {:?} key in the keyring", KeyPurpose::Macaroon),
+        }
+    }
+
+    async fn authenticate(key: &str, keyring: &Keyring, storage: &dyn Storage) -> Result<SgStatusCode> {
+        match Macaroon::decode(key, keyring).await {
+            (SgStatusCode::AuthenticToken, Some(macaroon)) => {
+                match RevocationList::is_revoked(&macaroon.nonce, storage).await? {
+                    true => Ok(SgStatusCode::Revoked),
+                    false => Ok(SgStatusCode::AuthenticToken),
+                }
+            },
+            (status, _) => Ok(status),
+        }
+    }
+
+    /// Only checks the signature, leases and revocation, use `Macaroon::verify()` to evaluate the other caveats
+    async fn authorize(key: &str, keyring: &Keyring, storage: &dyn Storage) -> Result<SgStatusCode> {
+        match <Macaroon as crate::global::KeyedSecurityCheck>::authenticate(key, keyring, storage).await? {
+            SgStatusCode::AuthenticToken => Ok(SgStatusCode::AccessGranted),
+            SgStatusCode::Rejected | SgStatusCode::Revoked => Ok(SgStatusCode::AccessDenied),
+            status => Ok(status),
+        }
+    }
+
+    /// Put the nonce of a valid macaroon on the `RevocationList` until the earliest lease caveat it was issued with ends
+    async fn revoke(key: &str, keyring: &Keyring, storage: &dyn Storage) -> Result<SgStatusCode> {
+        match Macaroon::decode(key, keyring).await {
+            (SgStatusCode::AuthenticToken, Some(macaroon)) => {
+                RevocationList::revoke(&macaroon.nonce, &macaroon.root_lease(), storage).await
+            },
+            _ => Ok(SgStatusCode::Rejected),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{global::{KeyedSecurityCheck, REVOCATION_DOCUMENT}, MemPrngStore};
+    use futures_lite::future::block_on;
+    use secrecy::SecretVec;
+    use tai64::TAI64N;
+    use std::time::Duration;
+
+    async fn keyring() -> Keyring {
+        let keyring = Keyring::new();
+        keyring.install(KeyPurpose::Macaroon, "root-1", SecretVec::new(vec![3_u8; blake3::KEY_LEN])).await.unwrap();
+        keyring.activate(KeyPurpose::Macaroon, "root-1").await.unwrap();
+
+        keyring
+    }
+
+    #[test]
+    fn revoking_an_attenuated_copy_lasts_for_the_issued_lease() {
+        block_on(async {
+            let keyring = keyring().await;
+            let storage = MemPrngStore::new();
+            let issued_lease = Lease::DateExpiryTAI(TAI64N::now() + Duration::from_secs(3600));
+
+            let parent = Macaroon::new(Identifier::new("alice"), Role::User)
+                .attenuate(Caveat::Lease(issued_lease.clone())).unwrap()
+                .issue(&keyring, &storage).await.unwrap();
+
+            let child = Macaroon::parse(parent.expose_secret()).unwrap()
+                .attenuate(Caveat::Lease(Lease::DateExpiryTAI(TAI64N::now() + Duration::from_secs(60)))).unwrap()
+                .encode().unwrap();
+
+            assert!(matches!(Macaroon::revoke(child.expose_secret(), &keyring, &storage).await.unwrap(), SgStatusCode::Revoked));
+            assert!(matches!(Macaroon::authenticate(parent.expose_secret(), &keyring, &storage).await.unwrap(), SgStatusCode::Revoked));
+
+            let nonce = Macaroon::parse(parent.expose_secret()).unwrap().nonce;
+            let stored = storage.get(REVOCATION_DOCUMENT, blake3::hash(&nonce).as_bytes()).await.unwrap();
+            assert_eq!(stored, Some(Lease::to_header(&issued_lease)));
+        });
+    }
+
+    #[test]
+    fn the_issued_caveat_count_is_signed() {
+        block_on(async {
+            let keyring = keyring().await;
+            let storage = MemPrngStore::new();
+
+            let token = Macaroon::new(Identifier::new("alice"), Role::User)
+                .attenuate(Caveat::RoleCeiling(Role::User)).unwrap()
+                .issue(&keyring, &storage).await.unwrap();
+            assert!(matches!(Macaroon::decode(token.expose_secret(), &keyring).await, (SgStatusCode::AuthenticToken, Some(_))));
+
+            let mut tampered = Macaroon::parse(token.expose_secret()).unwrap();
+            tampered.issued_caveats = 0;
+            let tampered = tampered.encode().unwrap();
+            assert!(matches!(Macaroon::decode(tampered.expose_secret(), &keyring).await, (SgStatusCode::Rejected, None)));
+        });
+    }
+}
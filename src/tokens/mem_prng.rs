@@ -0,0 +1,170 @@
+use crate::{global::{
+    SgStatusCode,
+    SecurityCheck,
+}, storage::Storage, PrngToken};
+
+use secrecy::SecretString;
+use async_lock::RwLock;
+use async_trait::async_trait;
+use anyhow::Result;
+use std::collections::HashMap;
+
+type Namespace = HashMap<Vec<u8>, Vec<u8>>;
+
+/// ### An in-memory token store
+/// Holds `PrngToken`s in a concurrent map instead of the `TuringDB` session document.
+/// The semantics of `issue`, `authenticate`, `authorize` and `revoke` are the same as
+/// the `SecurityCheck` implementation of `PrngToken` but nothing ever touches the disk,
+/// which makes it useful as a low latency session cache and for tests.
+/// It is also a `Storage` backend so it can be handed to any `SecurityCheck` implementation.
+#[derive(Debug, Default)]
+pub struct MemPrngStore {
+    namespaces: RwLock<HashMap<String, Namespace>>,
+}
+
+impl MemPrngStore {
+    /// Create an empty store
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create a token
+    pub async fn issue(&self, token: PrngToken) -> Result<SecretString> {
+        token.issue(self).await
+    }
+
+    pub async fn authenticate(&self, key: &str) -> Result<SgStatusCode> {
+        PrngToken::authenticate(key, self).await
+    }
+
+    pub async fn authorize(&self, key: &str) -> Result<SgStatusCode> {
+        PrngToken::authorize(key, self).await
+    }
+
+    pub async fn revoke(&self, key: &str) -> Result<SgStatusCode> {
+        PrngToken::revoke(key, self).await
+    }
+
+    /// The number of entries in a namespace
+    pub async fn len(&self, namespace: &str) -> usize {
+        self.namespaces.read().await
+            .get(namespace)
+            .map_or(0, |entries| entries.len())
+    }
+
+    /// Check whether a namespace has no entries
+    pub async fn is_empty(&self, namespace: &str) -> bool {
+        self.len(namespace).await == 0
+    }
+}
+
+#[async_trait]
+impl Storage for MemPrngStore {
+    async fn put(&self, namespace: &str, key: &[u8], value: &[u8]) -> Result<()> {
+        self.namespaces.write().await
+            .entry(namespace.into())
+            .or_default()
+            .insert(key.to_vec(), value.to_vec());
+
+        Ok(())
+    }
+
+    async fn get(&self, namespace: &str, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        Ok(self.namespaces.read().await
+            .get(namespace)
+            .and_then(|entries| entries.get(key).cloned()))
+    }
+
+    async fn delete(&self, namespace: &str, key: &[u8]) -> Result<bool> {
+        Ok(self.namespaces.write().await
+            .get_mut(namespace)
+            .and_then(|entries| entries.remove(key))
+            .is_some())
+    }
+
+    async fn scan(&self, namespace: &str) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        Ok(self.namespaces.read().await
+            .get(namespace)
+            .map(|entries| entries.iter().map(|(key, value)| (key.clone(), value.clone())).collect())
+            .unwrap_or_default())
+    }
+
+    async fn compare_and_swap(&self, namespace: &str, key: &[u8], current: Option<&[u8]>, new: Option<&[u8]>) -> Result<bool> {
+        let mut namespaces = self.namespaces.write().await;
+        let entries = namespaces.entry(namespace.into()).or_default();
+
+        if entries.get(key).map(|value| value.as_slice()) != current {
+            return Ok(false);
+        }
+
+        match new {
+            Some(value) => { entries.insert(key.to_vec(), value.to_vec()); },
+            None => { entries.remove(key); },
+        }
+
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::global::{to_blake3, Identifier, Lease, Role, GC_REGISTRY, TOKEN_SESSION_DOCUMENT};
+    use futures_lite::future::block_on;
+    use secrecy::ExposeSecret;
+
+    #[test]
+    fn tokens_round_trip_until_revoked() {
+        block_on(async {
+            let store = MemPrngStore::new();
+            let token = PrngToken::default().identifier(Identifier::new("alice")).role(Role::User);
+            let hashed_token = token.token_hash();
+            let key = store.issue(token).await.unwrap();
+
+            // The record is keyed by the hash and nothing else is kept
+            assert_eq!(to_blake3(&key).unwrap(), hashed_token);
+            assert!(store.get(TOKEN_SESSION_DOCUMENT, hashed_token.as_bytes()).await.unwrap().is_some());
+            assert_eq!(store.len(TOKEN_SESSION_DOCUMENT).await, 1);
+
+            assert!(matches!(store.authenticate(key.expose_secret()).await.unwrap(), SgStatusCode::AuthenticToken));
+            assert!(matches!(store.authorize(key.expose_secret()).await.unwrap(), SgStatusCode::AccessGranted));
+
+            assert!(matches!(store.revoke(key.expose_secret()).await.unwrap(), SgStatusCode::Revoked));
+            assert!(store.is_empty(TOKEN_SESSION_DOCUMENT).await);
+            assert!(store.is_empty(GC_REGISTRY).await);
+
+            assert!(matches!(store.authenticate(key.expose_secret()).await.unwrap(), SgStatusCode::Rejected));
+            assert!(matches!(store.authorize(key.expose_secret()).await.unwrap(), SgStatusCode::AccessDenied));
+            assert!(matches!(store.revoke(key.expose_secret()).await.unwrap(), SgStatusCode::Rejected));
+        });
+    }
+
+    #[test]
+    fn wrong_keys_are_rejected() {
+        block_on(async {
+            let store = MemPrngStore::new();
+            let key = store.issue(PrngToken::default().lease(Lease::Lifetime)).await.unwrap();
+            let wrong = hex::encode(blake3::hash(key.expose_secret().as_bytes()).as_bytes());
+
+            assert!(matches!(store.authenticate(&wrong).await.unwrap(), SgStatusCode::Rejected));
+            assert!(matches!(store.authorize(&wrong).await.unwrap(), SgStatusCode::AccessDenied));
+            assert!(matches!(store.revoke(&wrong).await.unwrap(), SgStatusCode::Rejected));
+            assert!(store.authenticate("not hex").await.is_err());
+
+            assert!(matches!(store.authenticate(key.expose_secret()).await.unwrap(), SgStatusCode::AuthenticToken));
+        });
+    }
+
+    #[test]
+    fn compare_and_swap_only_replaces_the_expected_value() {
+        block_on(async {
+            let store = MemPrngStore::new();
+
+            assert!(store.compare_and_swap("namespace", b"key", None, Some(&b"first"[..])).await.unwrap());
+            assert!(!store.compare_and_swap("namespace", b"key", None, Some(&b"second"[..])).await.unwrap());
+            assert!(!store.compare_and_swap("namespace", b"key", Some(&b"second"[..]), None).await.unwrap());
+            assert!(store.compare_and_swap("namespace", b"key", Some(&b"first"[..]), None).await.unwrap());
+            assert!(store.is_empty("namespace").await);
+        });
+    }
+}
@@ -1,4 +1,23 @@
 mod prng;
 mod csprng;
+mod mem_prng;
+mod heartbeat;
+mod cookie;
+mod aead;
+mod revocation;
+mod branca;
+mod paseto;
+mod jwt;
+mod macaroon;
 
-pub use prng::*;
\ No newline at end of file
+pub use prng::*;
+pub use csprng::*;
+pub use mem_prng::*;
+pub use heartbeat::*;
+pub use cookie::*;
+pub use aead::*;
+pub use revocation::*;
+pub use self::branca::*;
+pub use paseto::*;
+pub use jwt::*;
+pub use macaroon::*;
\ No newline at end of file
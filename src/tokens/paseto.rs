@@ -0,0 +1,489 @@
+use crate::{global::{
+    Identifier,
+    Lease,
+    Role,
+    SgStatusCode,
+}, storage::Storage, Keyring, KeyPurpose, RevocationList, RoleHierarchy};
+
+use pasetors::{
+    keys::{AsymmetricPublicKey, AsymmetricSecretKey, SymmetricKey},
+    token::UntrustedToken,
+    version4::{LocalToken, PublicToken, V4},
+    Local,
+    Public,
+};
+use chrono::{DateTime, SecondsFormat, Utc};
+use secrecy::{Secret, SecretString, ExposeSecret};
+use tai64::TAI64N;
+use anyhow::{Result, anyhow, bail};
+use serde::{Serialize, Deserialize};
+use std::{convert::TryFrom, time::SystemTime};
+
+/// Header of a v4.local token
+pub const PASETO_LOCAL_HEADER: &str = "v4.local.";
+/// Header of a v4.public token
+pub const PASETO_PUBLIC_HEADER: &str = "v4.public.";
+/// Length in bytes of a v4.local key
+pub const PASETO_LOCAL_KEY_LEN: usize = 32;
+/// Length in bytes of an Ed25519 seed, a v4.public signing key is the seed followed by the public key
+pub const PASETO_SEED_LEN: usize = 32;
+
+/// Which PASETO v4 purpose a token uses
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PasetoPurpose {
+    /// `v4.local`, encrypted with a shared key from `KeyPurpose::PasetoLocal`
+    Local,
+    /// `v4.public`, signed with an Ed25519 key from `KeyPurpose::PasetoSign`
+    Public,
+}
+
+/// The registered claims plus the `role` claim
+#[derive(Debug, Serialize, Deserialize)]
+struct PasetoClaims {
+    sub: String,
+    iat: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    exp: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    nbf: Option<String>,
+    role: String,
+}
+
+/// What is checked besides the authenticity of the token, `exp` and `nbf`
+#[derive(Debug, Clone, Default)]
+pub struct PasetoValidation {
+    implicit_assertion: Vec<u8>,
+    allow_lifetime: bool,
+}
+
+impl PasetoValidation {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// The implicit assertion the token was made with, empty by default
+    pub fn implicit_assertion(mut self, assertion: &[u8]) -> Self {
+        self.implicit_assertion = assertion.to_vec();
+
+        self
+    }
+    /// Accept tokens without an `exp` claim as `Lease::Lifetime` tokens, by default they are rejected
+    pub fn allow_lifetime(mut self) -> Self {
+        self.allow_lifetime = true;
+
+        self
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct PasetoFooter {
+    kid: String,
+}
+
+/// ### A PASETO v4 token
+/// The `Identifier` is the `sub` claim, the `Role` is the `role` claim by name, the issue time is `iat`
+/// and a `Lease::DateExpiryTAI` is `exp`, a `Lease::Lifetime` token has no `exp`.
+/// Times are RFC 3339 as the specification requires. Tokens made here carry the ID of their key
+/// in a `{"kid":"..."}` footer, tokens from partner services without it are tried against every key.
+/// `v4.public` tokens are verified with the `KeyPurpose::PasetoVerify` key named in the footer,
+/// falling back to the public half of a `KeyPurpose::PasetoSign` key with that ID.
+/// An implicit assertion is authenticated but not carried in the token, so the same bytes must be
+/// given to `decode()` in the `PasetoValidation`. Tokens without `exp` are only accepted by a
+/// `PasetoValidation` that allows them, the `KeyedSecurityCheck` implementation uses the default validation.
+/// Only `Lease::Lifetime` and `Lease::DateExpiryTAI` can be used
+#[derive(Debug)]
+pub struct PasetoToken {
+    purpose: PasetoPurpose,
+    identifier: Secret<Identifier>,
+    role: Secret<Role>,
+    lease: Secret<Lease>,
+    issued: TAI64N,
+    implicit_assertion: Vec<u8>,
+}
+
+impl PasetoToken {
+    pub fn new(purpose: PasetoPurpose, identifier: Identifier, role: Role, lease: Lease) -> Self {
+        Self {
+            purpose,
+            identifier: Secret::new(identifier),
+            role: Secret::new(role),
+            lease: Secret::new(lease),
+            issued: TAI64N::now(),
+            implicit_assertion: Vec::new(),
+        }
+    }
+
+    /// Bind the token to an implicit assertion, for example the ID of the partner it is meant for
+    pub fn implicit_assertion(mut self, assertion: &[u8]) -> Self {
+        self.implicit_assertion = assertion.to_vec();
+
+        self
+    }
+
+    pub fn get_purpose(&self) -> PasetoPurpose {
+        self.purpose
+    }
+
+    pub fn get_identifier(&self) -> &Identifier {
+        self.identifier.expose_secret()
+    }
+
+    pub fn get_role(&self) -> &Role {
+        self.role.expose_secret()
+    }
+
+    pub fn get_lease(&self) -> &Lease {
+        self.lease.expose_secret()
+    }
+
+    pub fn get_issued(&self) -> TAI64N {
+        self.issued
+    }
+
+    /// Verify a token with the implicit assertion of `validation` and check its claims without consulting the `RevocationList`.
+    /// Returns `SgStatusCode::AuthenticToken` with the token if it is valid,
+    /// `SgStatusCode::Expired` if its `exp` has passed, `SgStatusCode::BadDeserialize` if the claims
+    /// are missing or malformed and `SgStatusCode::Rejected` otherwise, including before its `nbf`
+    /// and without an `exp` unless `validation` allows it
+    pub async fn decode(token: &str, validation: &PasetoValidation, keyring: &Keyring) -> (SgStatusCode, Option<PasetoToken>) {
+        let implicit_assertion = validation.implicit_assertion.as_slice();
+
+        let (purpose, payload) = if token.starts_with(PASETO_LOCAL_HEADER) {
+            (PasetoPurpose::Local, PasetoToken::open_local(token, implicit_assertion, keyring).await)
+        }else if token.starts_with(PASETO_PUBLIC_HEADER) {
+            (PasetoPurpose::Public, PasetoToken::open_public(token, implicit_assertion, keyring).await)
+        }else {
+            return (SgStatusCode::Rejected, None);
+        };
+
+        let claims = match payload.map(|payload| serde_json::from_str::<PasetoClaims>(&payload)) {
+            Some(Ok(claims)) => claims,
+            Some(Err(_)) => return (SgStatusCode::BadDeserialize, None),
+            None => return (SgStatusCode::Rejected, None),
+        };
+
+        let decoded = match PasetoToken::from_claims(purpose, claims, validation) {
+            Some(decoded) => decoded,
+            None => return (SgStatusCode::Rejected, None),
+        };
+
+        match decoded.get_lease().is_expired() {
+            true => (SgStatusCode::Expired, None),
+            false => (SgStatusCode::AuthenticToken, Some(decoded)),
+        }
+    }
+
+    /// Decode a token and check that it is not on the `RevocationList`
+    pub async fn verify(token: &str, validation: &PasetoValidation, keyring: &Keyring, storage: &dyn Storage) -> Result<(SgStatusCode, Option<PasetoToken>)> {
+        match PasetoToken::decode(token, validation, keyring).await {
+            (SgStatusCode::AuthenticToken, Some(decoded)) => {
+                match RevocationList::is_revoked(token.as_bytes(), storage).await? {
+                    true => Ok((SgStatusCode::Revoked, None)),
+                    false => Ok((SgStatusCode::AuthenticToken, Some(decoded))),
+                }
+            },
+            outcome => Ok(outcome),
+        }
+    }
+
+    /// Authorize a token only if its role has the permissions of `required_role` in `hierarchy`
+    pub async fn authorize_for(token: &str, validation: &PasetoValidation, required_role: &Role, hierarchy: &RoleHierarchy, keyring: &Keyring, storage: &dyn Storage) -> Result<SgStatusCode> {
+        match PasetoToken::verify(token, validation, keyring, storage).await? {
+            (SgStatusCode::AuthenticToken, Some(decoded)) => match hierarchy.satisfies(decoded.get_role(), required_role) {
+                true => Ok(SgStatusCode::AccessGranted),
+                false => Ok(SgStatusCode::AccessDenied),
+            },
+            (SgStatusCode::Rejected, _) | (SgStatusCode::Revoked, _) => Ok(SgStatusCode::AccessDenied),
+            (status, _) => Ok(status),
+        }
+    }
+
+    async fn open_local(token: &str, implicit_assertion: &[u8], keyring: &Keyring) -> Option<String> {
+        let untrusted = UntrustedToken::<Local, V4>::try_from(token).ok()?;
+        let footer = untrusted.untrusted_footer().to_vec();
+
+        let open = |key: &[u8]| {
+            let key = SymmetricKey::<V4>::from(key).ok()?;
+
+            LocalToken::decrypt(&key, &untrusted, Some(&footer), Some(implicit_assertion))
+                .ok()
+                .map(|trusted| trusted.payload().to_string())
+        };
+
+        match PasetoToken::footer_kid(&footer) {
+            Some(kid) => keyring.with_key(KeyPurpose::PasetoLocal, &kid, open).await.flatten(),
+            None => keyring.find_map(KeyPurpose::PasetoLocal, open).await,
+        }
+    }
+
+    async fn open_public(token: &str, implicit_assertion: &[u8], keyring: &Keyring) -> Option<String> {
+        let untrusted = UntrustedToken::<Public, V4>::try_from(token).ok()?;
+        let footer = untrusted.untrusted_footer().to_vec();
+
+        let open = |public_key: &[u8]| {
+            let public_key = AsymmetricPublicKey::<V4>::from(public_key).ok()?;
+
+            PublicToken::verify(&public_key, &untrusted, Some(&footer), Some(implicit_assertion))
+                .ok()
+                .map(|trusted| trusted.payload().to_string())
+        };
+
+        match PasetoToken::footer_kid(&footer) {
+            Some(kid) => {
+                match keyring.with_key(KeyPurpose::PasetoVerify, &kid, &open).await.flatten() {
+                    Some(payload) => Some(payload),
+                    None => keyring.with_key(KeyPurpose::PasetoSign, &kid, |key| {
+                        key.get(PASETO_SEED_LEN..).and_then(&open)
+                    }).await.flatten(),
+                }
+            },
+            None => keyring.find_map(KeyPurpose::PasetoVerify, &open).await,
+        }
+    }
+
+    fn footer_kid(footer: &[u8]) -> Option<String> {
+        serde_json::from_slice::<PasetoFooter>(footer)
+            .ok()
+            .map(|footer| footer.kid)
+    }
+
+    fn to_claims(&self) -> Result<PasetoClaims> {
+        self.get_lease().ensure_stateless()?;
+
+        let exp = match self.get_lease() {
+            Lease::DateExpiryTAI(expiry) => Some(PasetoToken::to_rfc3339(*expiry)?),
+            _ => None,
+        };
+
+        Ok(PasetoClaims {
+            sub: self.get_identifier().0.clone(),
+            iat: PasetoToken::to_rfc3339(self.issued)?,
+            exp,
+            nbf: None,
+            role: self.get_role().name().into(),
+        })
+    }
+
+    fn from_claims(purpose: PasetoPurpose, claims: PasetoClaims, validation: &PasetoValidation) -> Option<Self> {
+        if let Some(nbf) = claims.nbf {
+            if TAI64N::now() < PasetoToken::from_rfc3339(&nbf)? {
+                return None;
+            }
+        }
+
+        let lease = match claims.exp {
+            Some(exp) => Lease::DateExpiryTAI(PasetoToken::from_rfc3339(&exp)?),
+            None if validation.allow_lifetime => Lease::Lifetime,
+            None => return None,
+        };
+
+        let mut decoded = PasetoToken::new(purpose, Identifier::new(&claims.sub), Role::from_name(&claims.role), lease)
+            .implicit_assertion(&validation.implicit_assertion);
+        decoded.issued = PasetoToken::from_rfc3339(&claims.iat)?;
+
+        Some(decoded)
+    }
+
+    fn to_rfc3339(time: TAI64N) -> Result<String> {
+        let time = time.to_system_time().map_err(|error| anyhow!("{:?}", error))?;
+
+        Ok(DateTime::<Utc>::from(time).to_rfc3339_opts(SecondsFormat::Secs, true))
+    }
+
+    fn from_rfc3339(time: &str) -> Option<TAI64N> {
+        let time = DateTime::parse_from_rfc3339(time).ok()?;
+
+        Some(TAI64N::from_system_time(&SystemTime::from(time)))
+    }
+}
+
+use async_trait::async_trait;
+#[async_trait]
+impl crate::global::KeyedSecurityCheck for PasetoToken {
+    /// Encrypt or sign the token with the active key of its purpose, nothing is stored
+    async fn issue(self, keyring: &Keyring, _storage: &dyn Storage) -> Result<SecretString> {
+        let claims = serde_json::to_vec(&self.to_claims()?)?;
+        let implicit_assertion = self.implicit_assertion.as_slice();
+
+        let (key_purpose, token) = match self.purpose {
+            PasetoPurpose::Local => (KeyPurpose::PasetoLocal, keyring.with_active(KeyPurpose::PasetoLocal, |kid, key| {
+                let key = SymmetricKey::<V4>::from(key)
+                    .map_err(|_| anyhow!("The `{}` key must be {} bytes", kid, PASETO_LOCAL_KEY_LEN))?;
+                let footer = serde_json::to_vec(&PasetoFooter { kid: kid.into() })?;
+
+                LocalToken::encrypt(&key, &claims, Some(&footer), Some(implicit_assertion))
+                    .map_err(|error| anyhow!("Unable to encrypt the token: {:?}", error))
+            }).await),
+            PasetoPurpose::Public => (KeyPurpose::PasetoSign, keyring.with_active(KeyPurpose::PasetoSign, |kid, key| {
+                let key = AsymmetricSecretKey::<V4>::from(key)
+                    .map_err(|_| anyhow!("The `{}` key must be {} bytes", kid, PASETO_SEED_LEN * 2))?;
+                let footer = serde_json::to_vec(&PasetoFooter { kid: kid.into() })?;
+
+                PublicToken::sign(&key, &claims, Some(&footer), Some(implicit_assertion))
+                    .map_err(|error| anyhow!("Unable to sign the token: {:?}", error))
+            }).await),
+        };
+
+        match token {
+            Some(token) => Ok(SecretString::new(token?)),
+            None => bail!("There is no active {:?} key in the keyring", key_purpose),
+        }
+    }
+
+    async fn authenticate(key: &str, keyring: &Keyring, storage: &dyn Storage) -> Result<SgStatusCode> {
+        let (status, _) = PasetoToken::verify(key, &PasetoValidation::default(), keyring, storage).await?;
+
+        Ok(status)
+    }
+
+    async fn authorize(key: &str, keyring: &Keyring, storage: &dyn Storage) -> Result<SgStatusCode> {
+        match PasetoToken::verify(key, &PasetoValidation::default(), keyring, storage).await? {
+            (SgStatusCode::AuthenticToken, _) => Ok(SgStatusCode::AccessGranted),
+            (SgStatusCode::Rejected, _) | (SgStatusCode::Revoked, _) => Ok(SgStatusCode::AccessDenied),
+            (status, _) => Ok(status),
+        }
+    }
+
+    /// Put a valid token on the `RevocationList` until its `exp`
+    async fn revoke(key: &str, keyring: &Keyring, storage: &dyn Storage) -> Result<SgStatusCode> {
+        match PasetoToken::decode(key, &PasetoValidation::default(), keyring).await {
+            (SgStatusCode::AuthenticToken, Some(decoded)) => {
+                RevocationList::revoke(key.as_bytes(), decoded.get_lease(), storage).await
+            },
+            _ => Ok(SgStatusCode::Rejected),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{KeyedSecurityCheck, MemPrngStore};
+    use futures_lite::future::block_on;
+    use secrecy::SecretVec;
+    use std::time::Duration;
+
+    // Test vectors 4-E-1 and 4-S-2 of the PASETO specification
+    const LOCAL_KEY: &str = "707172737475767778797a7b7c7d7e7f808182838485868788898a8b8c8d8e8f";
+    const LOCAL_TOKEN: &str = "v4.local.AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAQAr68PS4AXe7If_ZgesdkUMvSwscFlAl1pk5HC0e8kApeaqMfGo_7OpBnwJOAbY9V7WU6abu74MmcUE8YWAiaArVI8XJ5hOb_4v9RmDkneN0S92dx0OW4pgy7omxgf3S8c3LlQg";
+    const LOCAL_PAYLOAD: &str = r#"{"data":"this is a secret message","exp":"2022-01-01T00:00:00+00:00"}"#;
+    const SECRET_KEY: &str = "b4cbfb43df4ce210727d953e4a713307fa19bb7d9f85041438d9e11b942a37741eb9dbbbbc047c03fd70604e0071f0987e16b28b757225c11f00415d0e20b1a2";
+    const PUBLIC_KEY: &str = "1eb9dbbbbc047c03fd70604e0071f0987e16b28b757225c11f00415d0e20b1a2";
+    const PUBLIC_KID: &str = "zVhMiPBP9fRf2snEcT7gFTioeA9COcNy9DfgL1W60haN";
+    const PUBLIC_TOKEN: &str = "v4.public.eyJkYXRhIjoidGhpcyBpcyBhIHNpZ25lZCBtZXNzYWdlIiwiZXhwIjoiMjAyMi0wMS0wMVQwMDowMDowMCswMDowMCJ9v3Jt8mx_TdM2ceTGoqwrh4yDFn0XsHvvV_D0DtwQxVrJEBMl0F2caAdgnpKlt4p7xBnx1HcO-SPo8FPp214HDw.eyJraWQiOiJ6VmhNaVBCUDlmUmYyc25FY1Q3Z0ZUaW9lQTlDT2NOeTlEZmdMMVc2MGhhTiJ9";
+    const PUBLIC_PAYLOAD: &str = r#"{"data":"this is a signed message","exp":"2022-01-01T00:00:00+00:00"}"#;
+
+    async fn install(keyring: &Keyring, purpose: KeyPurpose, kid: &str, key: &str) {
+        keyring.install(purpose, kid, SecretVec::new(hex::decode(key).unwrap())).await.unwrap();
+        keyring.activate(purpose, kid).await.unwrap();
+    }
+
+    async fn keyring() -> Keyring {
+        let keyring = Keyring::new();
+        install(&keyring, KeyPurpose::PasetoLocal, "paseto-local-1", LOCAL_KEY).await;
+        install(&keyring, KeyPurpose::PasetoSign, "paseto-sign-1", SECRET_KEY).await;
+
+        keyring
+    }
+
+    async fn issue(purpose: PasetoPurpose, lease: Lease, keyring: &Keyring) -> SecretString {
+        PasetoToken::new(purpose, Identifier::new("alice"), Role::User, lease)
+            .issue(keyring, &MemPrngStore::new()).await
+            .unwrap()
+    }
+
+    #[test]
+    fn specification_vectors_open() {
+        block_on(async {
+            let keyring = Keyring::new();
+            install(&keyring, KeyPurpose::PasetoLocal, "paseto-local-1", LOCAL_KEY).await;
+            install(&keyring, KeyPurpose::PasetoVerify, PUBLIC_KID, PUBLIC_KEY).await;
+
+            assert_eq!(PasetoToken::open_local(LOCAL_TOKEN, b"", &keyring).await.as_deref(), Some(LOCAL_PAYLOAD));
+            assert_eq!(PasetoToken::open_public(PUBLIC_TOKEN, b"", &keyring).await.as_deref(), Some(PUBLIC_PAYLOAD));
+
+            // The vectors are authentic but carry no `sub`, `iat` or `role`
+            let validation = PasetoValidation::new();
+            assert!(matches!(PasetoToken::decode(LOCAL_TOKEN, &validation, &keyring).await, (SgStatusCode::BadDeserialize, None)));
+            assert!(matches!(PasetoToken::decode(PUBLIC_TOKEN, &validation, &keyring).await, (SgStatusCode::BadDeserialize, None)));
+
+            assert_eq!(PasetoToken::open_public(PUBLIC_TOKEN, b"partner", &keyring).await, None);
+            let other = Keyring::new();
+            install(&other, KeyPurpose::PasetoLocal, "paseto-local-1", PUBLIC_KEY).await;
+            assert_eq!(PasetoToken::open_local(LOCAL_TOKEN, b"", &other).await, None);
+        });
+    }
+
+    #[test]
+    fn tokens_round_trip_with_their_implicit_assertion() {
+        block_on(async {
+            let keyring = keyring().await;
+            let lease = Lease::DateExpiryTAI(TAI64N::now() + Duration::from_secs(60));
+
+            for purpose in &[PasetoPurpose::Local, PasetoPurpose::Public] {
+                let token = PasetoToken::new(*purpose, Identifier::new("alice"), Role::User, lease.clone())
+                    .implicit_assertion(b"partner")
+                    .issue(&keyring, &MemPrngStore::new()).await
+                    .unwrap();
+
+                match PasetoToken::decode(token.expose_secret(), &PasetoValidation::new().implicit_assertion(b"partner"), &keyring).await {
+                    (SgStatusCode::AuthenticToken, Some(decoded)) => {
+                        assert_eq!(decoded.get_purpose(), *purpose);
+                        assert_eq!(decoded.get_identifier().0, "alice");
+                        assert_eq!(decoded.get_role(), &Role::User);
+                    },
+                    (status, _) => panic!("{:?}", status),
+                }
+
+                assert!(matches!(PasetoToken::decode(token.expose_secret(), &PasetoValidation::new(), &keyring).await, (SgStatusCode::Rejected, None)));
+            }
+        });
+    }
+
+    #[test]
+    fn tokens_without_exp_need_allow_lifetime() {
+        block_on(async {
+            let keyring = keyring().await;
+            let storage = MemPrngStore::new();
+
+            for purpose in &[PasetoPurpose::Local, PasetoPurpose::Public] {
+                let token = issue(*purpose, Lease::Lifetime, &keyring).await;
+
+                assert!(matches!(PasetoToken::decode(token.expose_secret(), &PasetoValidation::new(), &keyring).await, (SgStatusCode::Rejected, None)));
+                assert!(matches!(PasetoToken::authorize(token.expose_secret(), &keyring, &storage).await.unwrap(), SgStatusCode::AccessDenied));
+
+                match PasetoToken::decode(token.expose_secret(), &PasetoValidation::new().allow_lifetime(), &keyring).await {
+                    (SgStatusCode::AuthenticToken, Some(decoded)) => assert!(matches!(decoded.get_lease(), Lease::Lifetime)),
+                    (status, _) => panic!("{:?}", status),
+                }
+            }
+        });
+    }
+
+    #[test]
+    fn tokens_expire() {
+        block_on(async {
+            let keyring = keyring().await;
+            let expired = issue(PasetoPurpose::Local, Lease::DateExpiryTAI(TAI64N::from_system_time(&std::time::UNIX_EPOCH)), &keyring).await;
+
+            assert!(matches!(PasetoToken::decode(expired.expose_secret(), &PasetoValidation::new(), &keyring).await, (SgStatusCode::Expired, None)));
+        });
+    }
+
+    #[test]
+    fn revoked_tokens_fail_verification() {
+        block_on(async {
+            let keyring = keyring().await;
+            let storage = MemPrngStore::new();
+            let validation = PasetoValidation::new();
+
+            for purpose in &[PasetoPurpose::Local, PasetoPurpose::Public] {
+                let token = issue(*purpose, Lease::DateExpiryTAI(TAI64N::now() + Duration::from_secs(60)), &keyring).await;
+
+                assert!(matches!(PasetoToken::authorize(token.expose_secret(), &keyring, &storage).await.unwrap(), SgStatusCode::AccessGranted));
+                PasetoToken::revoke(token.expose_secret(), &keyring, &storage).await.unwrap();
+                assert!(matches!(PasetoToken::verify(token.expose_secret(), &validation, &keyring, &storage).await.unwrap(), (SgStatusCode::Revoked, None)));
+                assert!(matches!(PasetoToken::authorize(token.expose_secret(), &keyring, &storage).await.unwrap(), SgStatusCode::AccessDenied));
+            }
+        });
+    }
+}
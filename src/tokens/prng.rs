@@ -5,13 +5,14 @@ use crate::{global::{
     SgStatusCode,
     Ping,
     TOKEN_SESSION_DOCUMENT,
-    TOKEN_DB_PATH,
+    GC_REGISTRY,
     Identifier,
-}, to_blake3};
+    Usage,
+    LeaseUse,
+}, to_blake3, storage::Storage, RoleHierarchy, Grant, AccessLevel, Scheme, SgConfig};
 
 use secrecy::{Secret, ExposeSecret, SecretString};
-use turingdb::TuringEngine;
-use custom_codes::DbOps;
+use tai64::TAI64N;
 use anyhow::Result;
 use serde::{Serialize, Deserialize};
 
@@ -23,84 +24,529 @@ pub struct PrngToken {
     lease: Secret<Lease>,
     last_active: Secret<TaiTimestamp>,
     ping: Ping,
+    grants: Vec<Grant>,
+    scheme: Option<Scheme>,
 }
 
 impl Default for PrngToken {
     fn default() -> Self {
-        let lease = tai64::TAI64N::now() + std::time::Duration::from_secs(timelite::LiteDuration::hours(24));
-        
+        PrngToken::from_config(&SgConfig::default())
+    }
+}
+
+impl PrngToken {
+    /// A `Role::User` token with the `SgConfig::default_lease()` of the configuration
+    pub fn from_config(config: &SgConfig) -> Self {
         Self {
             identifier: Secret::new(Identifier(String::default())),
             timestamp: Secret::new(TaiTimestamp::now()),
             role: Secret::new(Role::User),
-            lease: Secret::new(Lease::DateExpiryTAI(lease)),
+            lease: Secret::new(config.default_lease()),
             last_active: Secret::new(TaiTimestamp::now()),
             ping: Ping::Unreachable,
+            grants: Vec::new(),
+            scheme: None,
         }
     }
-}
 
-use async_trait::async_trait;
-#[async_trait]
-impl crate::global::SecurityCheck for PrngToken {
-    /// Create a token
-    async fn issue(self, db_engine: &TuringEngine) -> Result<SecretString> {
-        let data = bincode::serialize::<Self>(&self)?;
+    /// Replace the default `identifier`
+    pub fn identifier(mut self, identifier: Identifier) -> Self {
+        self.identifier = Secret::new(identifier);
+
+        self
+    }
+    /// Replace the default `Role::User`
+    pub fn role(mut self, role: Role) -> Self {
+        self.role = Secret::new(role);
+
+        self
+    }
+    /// Replace the default `Lease`, see `SgConfig::default_lease()`
+    pub fn lease(mut self, lease: Lease) -> Self {
+        self.lease = Secret::new(lease);
+
+        self
+    }
+
+    /// Add a `Grant`, a token without grants is not allowed any resource by `authorize_resource`
+    pub fn grant(mut self, grant: Grant) -> Self {
+        self.grants.push(grant);
+
+        self
+    }
+
+    /// Bind the token to a `Scheme` so it can not be replayed against another scheme
+    pub fn scheme(mut self, scheme: Scheme) -> Self {
+        self.scheme = Some(scheme);
+
+        self
+    }
 
-        // Take only the `identifier`, `timestamp`, `role` and `lease`
+    /// Hash the token into its storage key.
+    /// Takes only the `identifier`, `timestamp`, `role` and `lease`
+    pub fn token_hash(&self) -> blake3::Hash {
         let mut token_hash = blake3::Hasher::new();
         token_hash.update(self.identifier.expose_secret().0.as_bytes());
         token_hash.update(self.timestamp.expose_secret().get_bytes().expose_secret());
         token_hash.update(&Role::to_header(self.role.expose_secret()));
         token_hash.update(&Lease::to_header(self.lease.expose_secret()));
 
-        let hashed_token = token_hash.finalize();
+        token_hash.finalize()
+    }
 
-        TuringEngine::field_insert(db_engine, 
-            TOKEN_DB_PATH.as_ref(),
-            TOKEN_SESSION_DOCUMENT.as_ref(),
-            hashed_token.as_bytes(),
-            &data,
-        ).await?;
+    /// Store the token under `hashed_token` and index it in the `GC_REGISTRY` if its lease is bound to a date
+    pub async fn store(&self, hashed_token: &blake3::Hash, storage: &dyn Storage) -> Result<()> {
+        let data = bincode::serialize::<Self>(self)?;
 
+        storage.put(TOKEN_SESSION_DOCUMENT, hashed_token.as_bytes(), &data).await?;
 
-        Ok(SecretString::new(hex::encode(hashed_token.as_bytes())))
+        if let Lease::DateExpiryTAI(_) = self.get_lease() {
+            storage.put(GC_REGISTRY, hashed_token.as_bytes(), &Lease::to_header(self.get_lease())).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Remove the token stored under `hashed_token` together with its `GC_REGISTRY` entry.
+    /// Returns `true` if the token existed
+    pub async fn remove(hashed_token: &blake3::Hash, storage: &dyn Storage) -> Result<bool> {
+        storage.delete(GC_REGISTRY, hashed_token.as_bytes()).await?;
+
+        storage.delete(TOKEN_SESSION_DOCUMENT, hashed_token.as_bytes()).await
+    }
+
+    /// Remove the token stored under `hashed_token` together with its `GC_REGISTRY` entry,
+    /// only if the stored record is still `data`. Returns `true` if it was removed
+    async fn remove_unchanged(hashed_token: &blake3::Hash, data: &[u8], storage: &dyn Storage) -> Result<bool> {
+        let removed = storage.compare_and_swap(TOKEN_SESSION_DOCUMENT, hashed_token.as_bytes(), Some(data), None).await?;
+
+        if removed {
+            storage.delete(GC_REGISTRY, hashed_token.as_bytes()).await?;
+        }
+
+        Ok(removed)
+    }
+
+    pub fn get_identifier(&self) -> &Identifier {
+        self.identifier.expose_secret()
+    }
+
+    pub fn get_role(&self) -> &Role {
+        self.role.expose_secret()
+    }
+
+    pub fn get_lease(&self) -> &Lease {
+        self.lease.expose_secret()
+    }
+
+    pub fn get_grants(&self) -> &[Grant] {
+        &self.grants
+    }
+
+    pub fn get_scheme(&self) -> Option<&Scheme> {
+        self.scheme.as_ref()
+    }
+
+    /// Check whether any `Grant` of the token allows `permission` over `resource`
+    pub fn allows(&self, resource: &str, permission: AccessLevel) -> bool {
+        self.grants.iter().any(|grant| grant.allows(resource, permission))
+    }
+
+    /// Decode the token stored under `hashed_token` and check its `Lease`.
+    /// Returns `SgStatusCode::AuthenticToken` with the token if the lease is still valid,
+    /// `SgStatusCode::Expired` if the lease has passed, removing the token if `on_expiry` is `ExpiryAction::Revoke`,
+    /// `SgStatusCode::Rejected` if there is no such token and `SgStatusCode::BadDeserialize` if the record is corrupted
+    pub async fn fetch(hashed_token: &blake3::Hash, storage: &dyn Storage, on_expiry: ExpiryAction) -> Result<(SgStatusCode, Option<PrngToken>)> {
+        let data = match storage.get(TOKEN_SESSION_DOCUMENT, hashed_token.as_bytes()).await? {
+            Some(data) => data,
+            None => return Ok((SgStatusCode::Rejected, None)),
+        };
+
+        let token = match bincode::deserialize::<PrngToken>(&data) {
+            Ok(token) => token,
+            Err(_) => return Ok((SgStatusCode::BadDeserialize, None)),
+        };
+
+        if token.get_lease() == &Lease::Corrupted {
+            return Ok((SgStatusCode::Rejected, None));
+        }
+
+        if token.get_lease().is_expired() {
+            if on_expiry == ExpiryAction::Revoke {
+                PrngToken::remove_unchanged(hashed_token, &data, storage).await?;
+            }
+
+            return Ok((SgStatusCode::Expired, None));
+        }
+
+        Ok((SgStatusCode::AuthenticToken, Some(token)))
+    }
+
+    /// `authenticate` using the hash of the token.
+    /// A `Lease::FirstAccess` token is consumed by a successful authentication
+    pub async fn authenticate_hash(hashed_token: &blake3::Hash, storage: &dyn Storage, on_expiry: ExpiryAction) -> Result<SgStatusCode> {
+        match PrngToken::fetch(hashed_token, storage, on_expiry).await? {
+            (SgStatusCode::AuthenticToken, Some(token)) => {
+                if token.get_lease() != &Lease::FirstAccess {
+                    return Ok(SgStatusCode::AuthenticToken);
+                }
+
+                // Authentication does not name a scheme, a bound token is checked against its own
+                match PrngToken::consume(hashed_token, Usage::Access, token.get_scheme(), storage, on_expiry).await? {
+                    SgStatusCode::AccessGranted => Ok(SgStatusCode::AuthenticToken),
+                    SgStatusCode::AccessDenied => Ok(SgStatusCode::Rejected),
+                    status => Ok(status),
+                }
+            },
+            (status, _) => Ok(status),
+        }
+    }
+
+    /// `authorize` using the hash of the token.
+    /// A `Lease::FirstAccess` token is consumed by a successful authorization.
+    /// A token bound to a `Scheme` is denied, it has to be authorized with `authorize_scheme`
+    pub async fn authorize_hash(hashed_token: &blake3::Hash, storage: &dyn Storage, on_expiry: ExpiryAction) -> Result<SgStatusCode> {
+        PrngToken::consume(hashed_token, Usage::Access, None, storage, on_expiry).await
+    }
+
+    /// Authorize a `Usage` of the token with hex `key`, consuming usage-counted leases.
+    /// A token bound to a `Scheme` is denied
+    pub async fn authorize_usage(key: &str, usage: Usage, storage: &dyn Storage) -> Result<SgStatusCode> {
+        let hashed_token = to_blake3(&SecretString::new(key.into()))?;
+
+        PrngToken::consume(&hashed_token, usage, None, storage, ExpiryAction::default()).await
+    }
+
+    /// Authorize the token with hex `key` only if its role has the permissions of `required_role` in `hierarchy`.
+    /// A token bound to a `Scheme` is denied
+    pub async fn authorize_for(key: &str, required_role: &Role, hierarchy: &RoleHierarchy, storage: &dyn Storage) -> Result<SgStatusCode> {
+        let hashed_token = to_blake3(&SecretString::new(key.into()))?;
+
+        PrngToken::authorize_role_hash(&hashed_token, required_role, hierarchy, storage).await
+    }
+
+    /// `authorize_for` using the hash of the token
+    pub async fn authorize_role_hash(hashed_token: &blake3::Hash, required_role: &Role, hierarchy: &RoleHierarchy, storage: &dyn Storage) -> Result<SgStatusCode> {
+        match PrngToken::fetch(hashed_token, storage, ExpiryAction::default()).await? {
+            (SgStatusCode::AuthenticToken, Some(token)) => {
+                if !hierarchy.satisfies(token.get_role(), required_role) {
+                    return Ok(SgStatusCode::AccessDenied);
+                }
+
+                PrngToken::authorize_hash(hashed_token, storage, ExpiryAction::default()).await
+            },
+            (SgStatusCode::Rejected, _) => Ok(SgStatusCode::AccessDenied),
+            (status, _) => Ok(status),
+        }
+    }
+
+    /// Authorize the token with hex `key` only if one of its grants allows `permission` over `resource`.
+    /// A token bound to a `Scheme` is denied
+    pub async fn authorize_resource(key: &str, resource: &str, permission: AccessLevel, storage: &dyn Storage) -> Result<SgStatusCode> {
+        let hashed_token = to_blake3(&SecretString::new(key.into()))?;
+
+        PrngToken::authorize_resource_hash(&hashed_token, resource, permission, storage).await
+    }
+
+    /// `authorize_resource` using the hash of the token
+    pub async fn authorize_resource_hash(hashed_token: &blake3::Hash, resource: &str, permission: AccessLevel, storage: &dyn Storage) -> Result<SgStatusCode> {
+        match PrngToken::fetch(hashed_token, storage, ExpiryAction::default()).await? {
+            (SgStatusCode::AuthenticToken, Some(token)) => {
+                if !token.allows(resource, permission) {
+                    return Ok(SgStatusCode::AccessDenied);
+                }
+
+                PrngToken::authorize_hash(hashed_token, storage, ExpiryAction::default()).await
+            },
+            (SgStatusCode::Rejected, _) => Ok(SgStatusCode::AccessDenied),
+            (status, _) => Ok(status),
+        }
     }
 
-    async fn authenticate(key: &str, db_engine: &TuringEngine) -> Result<SgStatusCode> {
+    /// Authorize the token with hex `key` for the `requested` scheme.
+    /// A token bound to a scheme is only allowed schemes that its bound scheme `covers`,
+    /// a token that is not bound to a scheme is allowed any scheme
+    pub async fn authorize_scheme(key: &str, requested: &Scheme, storage: &dyn Storage) -> Result<SgStatusCode> {
         let hashed_token = to_blake3(&SecretString::new(key.into()))?;
-        dbg!(&hashed_token);
-
-        match TuringEngine::field_get(db_engine, 
-            TOKEN_DB_PATH.as_ref(), 
-        TOKEN_SESSION_DOCUMENT.as_ref(), 
-        hashed_token.as_bytes(),
-        ).await?{
-            DbOps::FieldContents(_) => Ok(SgStatusCode::AuthenticToken),
-            _ => Ok(SgStatusCode::Rejected)
+
+        PrngToken::authorize_scheme_hash(&hashed_token, requested, storage).await
+    }
+
+    /// `authorize_scheme` using the hash of the token
+    pub async fn authorize_scheme_hash(hashed_token: &blake3::Hash, requested: &Scheme, storage: &dyn Storage) -> Result<SgStatusCode> {
+        PrngToken::consume(hashed_token, Usage::Access, Some(requested), storage, ExpiryAction::default()).await
+    }
+
+    /// Atomically authorize a `Usage` of the token stored under `hashed_token`.
+    /// `Lease::OnDownloads` and `Lease::OnUploads` are decremented for each matching usage and
+    /// the token is revoked when the counter reaches zero. `Lease::OnDownload`, `Lease::OnUpload`
+    /// and `Lease::FirstAccess` are revoked on their first matching usage.
+    /// The update is a compare-and-swap on the stored record so concurrent requests can never
+    /// spend the same usage twice, a request that loses the race retries against the new record.
+    /// A token bound to a `Scheme` is denied unless its bound scheme `covers` the `requested` scheme,
+    /// so it is always denied when the request does not name a scheme
+    pub async fn consume(hashed_token: &blake3::Hash, usage: Usage, requested: Option<&Scheme>, storage: &dyn Storage, on_expiry: ExpiryAction) -> Result<SgStatusCode> {
+        loop {
+            let data = match storage.get(TOKEN_SESSION_DOCUMENT, hashed_token.as_bytes()).await? {
+                Some(data) => data,
+                None => return Ok(SgStatusCode::AccessDenied),
+            };
+
+            let mut token = match bincode::deserialize::<PrngToken>(&data) {
+                Ok(token) => token,
+                Err(_) => return Ok(SgStatusCode::BadDeserialize),
+            };
+
+            if token.get_lease().is_expired() {
+                if on_expiry == ExpiryAction::Revoke {
+                    PrngToken::remove_unchanged(hashed_token, &data, storage).await?;
+                }
+
+                return Ok(SgStatusCode::Expired);
+            }
+
+            if let Some(bound) = token.get_scheme() {
+                if !requested.map_or(false, |requested| bound.covers(requested)) {
+                    return Ok(SgStatusCode::AccessDenied);
+                }
+            }
+
+            let swapped = match token.get_lease().next_use(usage) {
+                LeaseUse::Denied => return Ok(SgStatusCode::AccessDenied),
+                LeaseUse::Unchanged => return Ok(SgStatusCode::AccessGranted),
+                LeaseUse::Spent => {
+                    storage.compare_and_swap(TOKEN_SESSION_DOCUMENT, hashed_token.as_bytes(), Some(&data), None).await?
+                },
+                LeaseUse::Remaining(lease) => {
+                    token.lease = Secret::new(lease);
+                    let updated = bincode::serialize::<PrngToken>(&token)?;
+
+                    storage.compare_and_swap(TOKEN_SESSION_DOCUMENT, hashed_token.as_bytes(), Some(&data), Some(&updated)).await?
+                },
+            };
+
+            if swapped {
+                return Ok(SgStatusCode::AccessGranted);
+            }
         }
     }
 
-    async fn authorize(key: &str, db_engine: &TuringEngine) -> Result<SgStatusCode> {
-        match TuringEngine::field_get(db_engine, 
-            TOKEN_DB_PATH.as_ref(), 
-        TOKEN_SESSION_DOCUMENT.as_ref(), 
-        blake3::hash(key.as_bytes()).as_bytes()
-        ).await?{
-            DbOps::FieldContents(_) => Ok(SgStatusCode::AccessGranted),
-            _ => Ok(SgStatusCode::AccessDenied)
+    /// Record a heartbeat for the token stored under `hashed_token`, updating its `ping` and `last_active`.
+    /// A `Ping::Offline` revokes a `Lease::OnDisconnection` token and returns `SgStatusCode::Revoked`
+    pub async fn heartbeat(hashed_token: &blake3::Hash, ping: Ping, storage: &dyn Storage) -> Result<SgStatusCode> {
+        loop {
+            let data = match storage.get(TOKEN_SESSION_DOCUMENT, hashed_token.as_bytes()).await? {
+                Some(data) => data,
+                None => return Ok(SgStatusCode::Rejected),
+            };
+
+            let mut token = match bincode::deserialize::<PrngToken>(&data) {
+                Ok(token) => token,
+                Err(_) => return Ok(SgStatusCode::BadDeserialize),
+            };
+
+            if ping == Ping::Offline && token.get_lease() == &Lease::OnDisconnection {
+                if storage.compare_and_swap(TOKEN_SESSION_DOCUMENT, hashed_token.as_bytes(), Some(&data), None).await? {
+                    return Ok(SgStatusCode::Revoked);
+                }
+
+                continue;
+            }
+
+            token.ping = ping;
+            token.last_active = Secret::new(TaiTimestamp::now());
+            let updated = bincode::serialize::<PrngToken>(&token)?;
+
+            if storage.compare_and_swap(TOKEN_SESSION_DOCUMENT, hashed_token.as_bytes(), Some(&data), Some(&updated)).await? {
+                return Ok(SgStatusCode::AuthenticToken);
+            }
         }
     }
 
-    async fn revoke(key: &str, db_engine: &TuringEngine) -> Result<SgStatusCode> {
-        match TuringEngine::field_remove(db_engine, 
-            TOKEN_DB_PATH.as_ref(), 
-        TOKEN_SESSION_DOCUMENT.as_ref(), 
-        blake3::hash(key.as_bytes()).as_bytes()
-        ).await?{
-            DbOps::FieldContents(_) => Ok(SgStatusCode::Revoked),
-            _ => Ok(SgStatusCode::Rejected)
+    pub fn get_ping(&self) -> Ping {
+        self.ping
+    }
+
+    /// The time of the last heartbeat, or of issuing if the token never had one
+    pub fn get_last_active(&self) -> TAI64N {
+        self.last_active.expose_secret().get_tai()
+    }
+}
+
+/// What to do with a token whose `Lease` is found to have expired while reading it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExpiryAction {
+    /// Leave the token in storage, the garbage collector will remove it
+    Retain,
+    /// Remove the token from storage immediately
+    Revoke,
+}
+
+impl Default for ExpiryAction {
+    fn default() -> Self {
+        ExpiryAction::Retain
+    }
+}
+
+use async_trait::async_trait;
+#[async_trait]
+impl crate::global::SecurityCheck for PrngToken {
+    /// Create a token
+    async fn issue(self, storage: &dyn Storage) -> Result<SecretString> {
+        let hashed_token = self.token_hash();
+
+        self.store(&hashed_token, storage).await?;
+
+        Ok(SecretString::new(hex::encode(hashed_token.as_bytes())))
+    }
+
+    async fn authenticate(key: &str, storage: &dyn Storage) -> Result<SgStatusCode> {
+        let hashed_token = to_blake3(&SecretString::new(key.into()))?;
+
+        PrngToken::authenticate_hash(&hashed_token, storage, ExpiryAction::default()).await
+    }
+
+    async fn authorize(key: &str, storage: &dyn Storage) -> Result<SgStatusCode> {
+        let hashed_token = to_blake3(&SecretString::new(key.into()))?;
+
+        PrngToken::authorize_hash(&hashed_token, storage, ExpiryAction::default()).await
+    }
+
+    async fn revoke(key: &str, storage: &dyn Storage) -> Result<SgStatusCode> {
+        let hashed_token = to_blake3(&SecretString::new(key.into()))?;
+
+        match PrngToken::remove(&hashed_token, storage).await? {
+            true => Ok(SgStatusCode::Revoked),
+            false => Ok(SgStatusCode::Rejected)
         }
     }
 
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MemPrngStore;
+    use futures_lite::future::block_on;
+    use std::{sync::Arc, thread, time::UNIX_EPOCH};
+
+    #[test]
+    fn concurrent_consume_spends_a_single_download_once() {
+        let storage = Arc::new(MemPrngStore::new());
+        let token = PrngToken::default().lease(Lease::OnDownloads(1));
+        let hashed_token = token.token_hash();
+        block_on(token.store(&hashed_token, storage.as_ref())).unwrap();
+
+        let workers = (0..8)
+            .map(|_| {
+                let storage = Arc::clone(&storage);
+
+                thread::spawn(move || {
+                    block_on(PrngToken::consume(&hashed_token, Usage::Download, None, storage.as_ref(), ExpiryAction::default())).unwrap()
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let granted = workers.into_iter()
+            .map(|worker| worker.join().unwrap())
+            .filter(|status| matches!(status, SgStatusCode::AccessGranted))
+            .count();
+
+        assert_eq!(granted, 1);
+        assert!(block_on(storage.is_empty(TOKEN_SESSION_DOCUMENT)));
+    }
+
+    #[test]
+    fn consume_only_spends_the_matching_usage() {
+        let storage = MemPrngStore::new();
+        let token = PrngToken::default().lease(Lease::OnUploads(2));
+        let hashed_token = token.token_hash();
+        block_on(token.store(&hashed_token, &storage)).unwrap();
+
+        let download = block_on(PrngToken::consume(&hashed_token, Usage::Download, None, &storage, ExpiryAction::default())).unwrap();
+        assert!(matches!(download, SgStatusCode::AccessDenied));
+
+        for _ in 0..2 {
+            let upload = block_on(PrngToken::consume(&hashed_token, Usage::Upload, None, &storage, ExpiryAction::default())).unwrap();
+            assert!(matches!(upload, SgStatusCode::AccessGranted));
+        }
+
+        let spent = block_on(PrngToken::consume(&hashed_token, Usage::Upload, None, &storage, ExpiryAction::default())).unwrap();
+        assert!(matches!(spent, SgStatusCode::AccessDenied));
+    }
+
+    #[test]
+    fn fetch_reports_an_expired_lease() {
+        let storage = MemPrngStore::new();
+        let expired = Lease::DateExpiryTAI(TAI64N::from_system_time(&UNIX_EPOCH));
+        let token = PrngToken::default().lease(expired);
+        let hashed_token = token.token_hash();
+        block_on(token.store(&hashed_token, &storage)).unwrap();
+
+        let (status, token) = block_on(PrngToken::fetch(&hashed_token, &storage, ExpiryAction::Retain)).unwrap();
+        assert!(matches!(status, SgStatusCode::Expired));
+        assert!(token.is_none());
+        assert_eq!(block_on(storage.len(TOKEN_SESSION_DOCUMENT)), 1);
+
+        assert_eq!(block_on(storage.len(GC_REGISTRY)), 1);
+
+        let (status, _) = block_on(PrngToken::fetch(&hashed_token, &storage, ExpiryAction::Revoke)).unwrap();
+        assert!(matches!(status, SgStatusCode::Expired));
+        assert!(block_on(storage.is_empty(TOKEN_SESSION_DOCUMENT)));
+        assert!(block_on(storage.is_empty(GC_REGISTRY)));
+
+        let (status, _) = block_on(PrngToken::fetch(&hashed_token, &storage, ExpiryAction::Retain)).unwrap();
+        assert!(matches!(status, SgStatusCode::Rejected));
+    }
+
+    #[test]
+    fn consume_revokes_an_expired_lease_with_its_registry_entry() {
+        let storage = MemPrngStore::new();
+        let expired = Lease::DateExpiryTAI(TAI64N::from_system_time(&UNIX_EPOCH));
+        let token = PrngToken::default().lease(expired);
+        let hashed_token = token.token_hash();
+        block_on(token.store(&hashed_token, &storage)).unwrap();
+
+        let retained = block_on(PrngToken::consume(&hashed_token, Usage::Access, None, &storage, ExpiryAction::Retain)).unwrap();
+        assert!(matches!(retained, SgStatusCode::Expired));
+        assert_eq!(block_on(storage.len(GC_REGISTRY)), 1);
+
+        let revoked = block_on(PrngToken::consume(&hashed_token, Usage::Access, None, &storage, ExpiryAction::Revoke)).unwrap();
+        assert!(matches!(revoked, SgStatusCode::Expired));
+        assert!(block_on(storage.is_empty(TOKEN_SESSION_DOCUMENT)));
+        assert!(block_on(storage.is_empty(GC_REGISTRY)));
+    }
+
+    #[test]
+    fn fetch_returns_a_live_token() {
+        let storage = MemPrngStore::new();
+        let token = PrngToken::default().role(Role::Admin);
+        let hashed_token = token.token_hash();
+        block_on(token.store(&hashed_token, &storage)).unwrap();
+
+        let (status, token) = block_on(PrngToken::fetch(&hashed_token, &storage, ExpiryAction::default())).unwrap();
+        assert!(matches!(status, SgStatusCode::AuthenticToken));
+        assert_eq!(token.unwrap().get_role(), &Role::Admin);
+    }
+
+    #[test]
+    fn scheme_bound_tokens_need_a_covered_scheme() {
+        let storage = MemPrngStore::new();
+        let bound = "https:example.com:443/api".parse::<Scheme>().unwrap();
+        let token = PrngToken::default().scheme(bound).grant(Grant::new("/**", &AccessLevel::all()));
+        let hashed_token = token.token_hash();
+        block_on(token.store(&hashed_token, &storage)).unwrap();
+
+        let covered = "https:example.com:443/api/users".parse::<Scheme>().unwrap();
+        let outside = "https:example.com:443/admin".parse::<Scheme>().unwrap();
+
+        block_on(async {
+            assert!(matches!(PrngToken::authorize_hash(&hashed_token, &storage, ExpiryAction::default()).await.unwrap(), SgStatusCode::AccessDenied));
+            assert!(matches!(PrngToken::authorize_resource_hash(&hashed_token, "/files", AccessLevel::Read, &storage).await.unwrap(), SgStatusCode::AccessDenied));
+            assert!(matches!(PrngToken::authorize_role_hash(&hashed_token, &Role::User, &RoleHierarchy::default(), &storage).await.unwrap(), SgStatusCode::AccessDenied));
+            assert!(matches!(PrngToken::consume(&hashed_token, Usage::Download, None, &storage, ExpiryAction::default()).await.unwrap(), SgStatusCode::AccessDenied));
+
+            assert!(matches!(PrngToken::authorize_scheme_hash(&hashed_token, &outside, &storage).await.unwrap(), SgStatusCode::AccessDenied));
+            assert!(matches!(PrngToken::authorize_scheme_hash(&hashed_token, &covered, &storage).await.unwrap(), SgStatusCode::AccessGranted));
+        });
+    }
+}
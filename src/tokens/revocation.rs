@@ -0,0 +1,31 @@
+use crate::{global::{
+    Lease,
+    SgStatusCode,
+    REVOCATION_DOCUMENT,
+}, storage::Storage};
+
+use anyhow::Result;
+
+/// ### A stored deny list for stateless tokens
+/// Entries are keyed by the `blake3` hash of a token or of its unique ID and hold the lease header
+/// of the revoked token so the garbage collector can drop them once the token would have expired anyway
+pub struct RevocationList;
+
+impl RevocationList {
+    /// Add a token to the deny list
+    pub async fn revoke(id: &[u8], lease: &Lease, storage: &dyn Storage) -> Result<SgStatusCode> {
+        let key = blake3::hash(id);
+
+        match storage.compare_and_swap(REVOCATION_DOCUMENT, key.as_bytes(), None, Some(&Lease::to_header(lease))).await? {
+            true => Ok(SgStatusCode::Revoked),
+            false => Ok(SgStatusCode::Rejected),
+        }
+    }
+
+    /// Check whether a token is on the deny list
+    pub async fn is_revoked(id: &[u8], storage: &dyn Storage) -> Result<bool> {
+        let key = blake3::hash(id);
+
+        Ok(storage.get(REVOCATION_DOCUMENT, key.as_bytes()).await?.is_some())
+    }
+}